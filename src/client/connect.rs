@@ -1,32 +1,73 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, OnceLock, Weak,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
 use base64::{Engine, engine::general_purpose};
 use ffmpeg_next as ffmpeg;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, broadcast, mpsc};
+use tracing::{debug, error, info, trace, warn};
 use webrtc::{
-    peer_connection::sdp::session_description::RTCSessionDescription,
-    rtp::{codecs::h264::H264Packet, packetizer::Depacketizer},
+    peer_connection::{
+        RTCPeerConnection, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
     rtp_transceiver::rtp_codec::RTPCodecType,
     track::track_remote::TrackRemote,
 };
 
-use super::StreamFrame;
-use crate::shared::{MousePosition, SdpData, create_peer_connection};
+use super::{
+    ClientEvent, StreamFrame,
+    reorder::{ReorderBuffer, Reordered},
+};
+use crate::{
+    error::ClientError,
+    shared::{
+        DEFAULT_NACK_BUFFER, ErrorResponse, MousePosition, ProbeResult, SdpData, StreamInfo,
+        VideoCodec, create_peer_connection, init_ffmpeg, test_decode, test_encode,
+    },
+};
 
 #[derive(Debug, Clone)]
 struct WebRTCPacket {
+    codec: VideoCodec,
     data: Vec<u8>,
     timestamp: u32,
 }
 
+/// Minimum time between two keyframe requests, so a burst of losses sends one PLI.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
 pub async fn start_webrtc(
     password: Option<String>,
     address: SocketAddr,
     hwaccel: bool,
     frame_tx: mpsc::Sender<StreamFrame>,
-) -> Result<()> {
+    event_tx: mpsc::UnboundedSender<ClientEvent>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> Result<Option<StreamInfo>, ClientError> {
+    // offer only the codecs this machine can decode
+    let codecs = decodable_codecs(hwaccel)?;
+    if codecs.is_empty() {
+        let codecs = VideoCodec::ALL
+            .iter()
+            .map(VideoCodec::to_string)
+            .collect::<Vec<String>>();
+        return Err(ClientError::DecoderNotFound(codecs.join(", ")));
+    }
+    let codec_names = codecs
+        .iter()
+        .map(VideoCodec::to_string)
+        .collect::<Vec<String>>();
+    info!(codecs = codec_names.join(", "), "Offering codecs");
+
     let (packet_tx, packet_rx) = mpsc::channel::<WebRTCPacket>(2);
+    let (keyframe_tx, keyframe_rx) = mpsc::unbounded_channel::<()>();
     let mouse_position = Arc::new(Mutex::new(None));
 
     // spawn video processing task
@@ -36,11 +77,24 @@ pub async fn start_webrtc(
         packet_rx,
         frame_tx_clone,
         mouse_position_clone,
+        keyframe_tx.clone(),
         hwaccel,
     ));
 
     // create peer connection
-    let peer_connection = create_peer_connection().await?;
+    let codecs = codecs
+        .iter()
+        .map(|&codec| (codec, codec.sdp_fmtp_line()))
+        .collect::<Vec<(VideoCodec, String)>>();
+    let peer_connection = create_peer_connection(&codecs, DEFAULT_NACK_BUFFER).await?;
+
+    // send keyframe requests to the server as PLI
+    let video_ssrc = Arc::new(AtomicU32::new(0));
+    tokio::spawn(run_keyframe_requester(
+        Arc::downgrade(&peer_connection),
+        video_ssrc.clone(),
+        keyframe_rx,
+    ));
 
     // add transceiver for video
     peer_connection
@@ -50,31 +104,53 @@ pub async fn start_webrtc(
     // handle incoming tracks
     peer_connection.on_track(Box::new(move |track, _, _| {
         if track.kind() == RTPCodecType::Video {
+            video_ssrc.store(track.ssrc(), Ordering::Relaxed);
             let tx = packet_tx.clone();
-            tokio::spawn(process_video_track(track, tx));
+            tokio::spawn(process_video_track(track, tx, keyframe_tx.clone()));
+        }
+        Box::pin(async {})
+    }));
+
+    // report connection state changes
+    peer_connection.on_peer_connection_state_change(Box::new(move |s| {
+        let event = match s {
+            RTCPeerConnectionState::Connected => Some(ClientEvent::Connected),
+            RTCPeerConnectionState::Disconnected => Some(ClientEvent::Disconnected),
+            RTCPeerConnectionState::Failed => Some(ClientEvent::Failed),
+            RTCPeerConnectionState::Closed => Some(ClientEvent::Closed),
+            _ => None,
+        };
+        if let Some(event) = event {
+            let _ = event_tx.send(event);
         }
         Box::pin(async {})
     }));
 
+    // close the peer connection on shutdown, which ends the track and decoder tasks
+    let peer_connection_clone = peer_connection.clone();
+    tokio::spawn(async move {
+        let _ = shutdown_rx.recv().await;
+        if let Err(err) = peer_connection_clone.close().await {
+            warn!(error = %err, "Failed to close peer connection");
+        }
+    });
+
     // create mouse data channel
-    let mouse_channel = peer_connection
-        .create_data_channel("mouse", None)
-        .await
-        .unwrap();
+    let mouse_channel = peer_connection.create_data_channel("mouse", None).await?;
     mouse_channel.on_open(Box::new(|| {
-        println!("Mouse data channel opened");
+        info!("Mouse data channel opened");
         Box::pin(async {})
     }));
     let mouse_pos_clone = mouse_position.clone();
     mouse_channel.on_message(Box::new(move |msg| {
-        if let Ok(text) = String::from_utf8(msg.data.to_vec()) {
-            if let Ok(pos) = serde_json::from_str::<MousePosition>(&text) {
-                // println!("Received mouse position: x={}, y={}", pos.x, pos.y);
-                let mouse_pos = mouse_pos_clone.clone();
-                tokio::spawn(async move {
-                    *mouse_pos.lock().await = Some(pos);
-                });
-            }
+        if let Ok(text) = String::from_utf8(msg.data.to_vec())
+            && let Ok(pos) = serde_json::from_str::<MousePosition>(&text)
+        {
+            trace!(x = pos.x, y = pos.y, "Received mouse position");
+            let mouse_pos = mouse_pos_clone.clone();
+            tokio::spawn(async move {
+                *mouse_pos.lock().await = Some(pos);
+            });
         }
 
         Box::pin(async {})
@@ -86,13 +162,23 @@ pub async fn start_webrtc(
 
     // wait for ICE gathering to complete
     let mut gather_complete = peer_connection.gathering_complete_promise().await;
-    let local_description = peer_connection.local_description().await.unwrap();
-    let sdp = general_purpose::STANDARD.encode(serde_json::to_string(&local_description)?);
+    let local_description = peer_connection
+        .local_description()
+        .await
+        .ok_or_else(|| ClientError::InvalidSdp("Missing local description".to_string()))?;
+    let sdp = general_purpose::STANDARD.encode(
+        serde_json::to_string(&local_description)
+            .map_err(|e| ClientError::InvalidSdp(e.to_string()))?,
+    );
     let _ = gather_complete.recv().await;
 
-    println!("Sending SDP to server at {}...", address);
+    info!(%address, "Sending SDP to server");
 
-    let sdp_data = SdpData { sdp, password };
+    let sdp_data = SdpData {
+        sdp,
+        password,
+        stream: None,
+    };
     let client = reqwest::Client::new();
     let res = client
         .post(format!("http://{}:{}/sdp", address.ip(), address.port()))
@@ -101,104 +187,346 @@ pub async fn start_webrtc(
         .await?;
 
     if !res.status().is_success() {
-        eprintln!("Failed to connect to server: {}", res.status());
-        return Err(anyhow::anyhow!("Failed to connect to server"));
+        let status = res.status();
+        // the server describes the failure in a JSON body, fall back to the status text
+        let message = res
+            .json::<ErrorResponse>()
+            .await
+            .map(|body| body.error)
+            .unwrap_or_else(|_| {
+                status
+                    .canonical_reason()
+                    .unwrap_or("Unknown error")
+                    .to_string()
+            });
+        error!(%status, error = %message, "Server rejected the connection");
+        return Err(ClientError::ServerRejected { status, message });
     }
 
     // get answer
     let answer_text = res.text().await?;
-    let answer_sdp: SdpData = serde_json::from_str(&answer_text)?;
+    let answer_sdp: SdpData =
+        serde_json::from_str(&answer_text).map_err(|e| ClientError::InvalidSdp(e.to_string()))?;
 
     let answer: RTCSessionDescription = {
-        let decoded_sdp = general_purpose::STANDARD.decode(answer_sdp.sdp)?;
-        let decoded_sdp_str = String::from_utf8(decoded_sdp)?;
-        serde_json::from_str(&decoded_sdp_str)?
+        let decoded_sdp = general_purpose::STANDARD
+            .decode(answer_sdp.sdp)
+            .map_err(|e| ClientError::InvalidSdp(e.to_string()))?;
+        let decoded_sdp_str =
+            String::from_utf8(decoded_sdp).map_err(|e| ClientError::InvalidSdp(e.to_string()))?;
+        serde_json::from_str(&decoded_sdp_str)
+            .map_err(|e| ClientError::InvalidSdp(e.to_string()))?
     };
 
     peer_connection.set_remote_description(answer).await?;
 
-    println!("Connected to server at {}", address);
+    info!(%address, "Connected to server");
+    if let Some(stream) = &answer_sdp.stream {
+        info!(codec = %stream.codec, encoder = stream.encoder, "Server selected codec");
+    }
 
-    Ok(())
+    Ok(answer_sdp.stream)
 }
 
-async fn process_video_track(track: Arc<TrackRemote>, packet_tx: mpsc::Sender<WebRTCPacket>) {
-    let mut h264_packet = H264Packet::default();
+/// Send a PLI for every keyframe request, at most once per [`KEYFRAME_REQUEST_INTERVAL`].
+async fn run_keyframe_requester(
+    peer_connection: Weak<RTCPeerConnection>,
+    video_ssrc: Arc<AtomicU32>,
+    mut keyframe_rx: mpsc::UnboundedReceiver<()>,
+) {
+    let mut last_request: Option<Instant> = None;
+
+    while keyframe_rx.recv().await.is_some() {
+        if last_request.is_some_and(|last| last.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+            continue;
+        }
+        let Some(peer_connection) = peer_connection.upgrade() else {
+            break;
+        };
+
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc: video_ssrc.load(Ordering::Relaxed),
+        };
+        debug!(ssrc = pli.media_ssrc, "Requesting keyframe");
+        if let Err(err) = peer_connection.write_rtcp(&[Box::new(pli)]).await {
+            warn!(error = %err, "Failed to send keyframe request");
+        }
+        last_request = Some(Instant::now());
+    }
+}
+
+async fn process_video_track(
+    track: Arc<TrackRemote>,
+    packet_tx: mpsc::Sender<WebRTCPacket>,
+    keyframe_tx: mpsc::UnboundedSender<()>,
+) {
+    let mime_type = track.codec().capability.mime_type;
+    let Some(codec) = VideoCodec::from_mime_type(&mime_type) else {
+        error!(mime_type, "Unsupported video codec");
+        return;
+    };
+    info!(%codec, "Receiving video");
+
+    let mut depacketizer = codec.depacketizer();
     let mut frame_buf: Vec<u8> = Vec::with_capacity(1024 * 1024);
-    let start_code: &[u8] = &[0, 0, 0, 1];
+    let mut reorder_buffer = ReorderBuffer::default();
+    // after a loss, the rest of the broken frame is dropped up to its marker bit
+    let mut discarding = false;
 
     loop {
-        // read RTP packet from track
-        let (rtp_packet, _) = match track.read_rtp().await {
-            Ok(packet) => packet,
-            Err(e) => {
-                eprintln!("Error reading RTP packet: {}", e);
-                break;
+        // read RTP packet from track, or stop waiting for a missing one when it is overdue,
+        // as the next packet may be a second away on a static screen
+        let deadline = reorder_buffer.deadline();
+        tokio::select! {
+            read = track.read_rtp() => match read {
+                Ok((rtp_packet, _)) => reorder_buffer.push(rtp_packet),
+                Err(e) => {
+                    warn!(error = %e, "Error reading RTP packet");
+                    break;
+                }
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                if deadline.is_some() => {}
+        }
+
+        while let Some(reordered) = reorder_buffer.pop() {
+            let rtp_packet = match reordered {
+                Reordered::Packet(packet) => packet,
+                Reordered::Lost => {
+                    // the frame being built is broken and retransmission did not
+                    // recover it, drop it and ask for a keyframe instead
+                    debug!("Packet loss detected");
+                    frame_buf.clear();
+                    discarding = true;
+                    let _ = keyframe_tx.send(());
+                    continue;
+                }
+            };
+
+            if discarding {
+                discarding = !rtp_packet.header.marker;
+                continue;
             }
-        };
 
-        // depacketize RTP payload
-        if let Ok(payload) = h264_packet.depacketize(&rtp_packet.payload) {
-            if !payload.is_empty() {
-                // prepend every NAL unit with a start code
-                frame_buf.extend_from_slice(start_code);
+            // depacketize RTP payload, NAL units come out with their start codes
+            if let Ok(payload) = depacketizer.depacketize(&rtp_packet.payload) {
                 frame_buf.extend_from_slice(&payload);
             }
-        }
-
-        // send frame if marker bit is set
-        if rtp_packet.header.marker && !frame_buf.is_empty() {
-            let raw_packet = WebRTCPacket {
-                data: std::mem::take(&mut frame_buf),
-                timestamp: rtp_packet.header.timestamp,
-            };
 
-            if let Err(err) = packet_tx.send(raw_packet).await {
-                eprintln!("Failed to send frame: {}", err);
-                break;
+            // send frame if marker bit is set
+            if rtp_packet.header.marker && !frame_buf.is_empty() {
+                let raw_packet = WebRTCPacket {
+                    codec,
+                    data: std::mem::take(&mut frame_buf),
+                    timestamp: rtp_packet.header.timestamp,
+                };
+
+                if let Err(err) = packet_tx.send(raw_packet).await {
+                    warn!(error = %err, "Failed to send frame");
+                    return;
+                }
             }
         }
     }
 }
 
 #[cfg(target_os = "windows")]
-const HW_DECODERS: &[&str] = &[
+const H264_HW_DECODERS: &[&str] = &[
     "h264_cuvid",   // NVIDIA CUVID
     "h264_qsv",     // Intel Quick Sync Video
     "h264_d3d11va", // Microsoft D3D11VA (generic, works on most modern GPUs)
     "h264_dxva2",   // Microsoft DXVA2 (older alternative)
 ];
 
+#[cfg(target_os = "windows")]
+const H265_HW_DECODERS: &[&str] = &[
+    "hevc_cuvid",   // NVIDIA CUVID
+    "hevc_qsv",     // Intel Quick Sync Video
+    "hevc_d3d11va", // Microsoft D3D11VA (generic, works on most modern GPUs)
+    "hevc_dxva2",   // Microsoft DXVA2 (older alternative)
+];
+
+#[cfg(target_os = "windows")]
+const VP8_HW_DECODERS: &[&str] = &[
+    "vp8_cuvid", // NVIDIA CUVID
+    "vp8_qsv",   // Intel Quick Sync Video
+];
+
+#[cfg(target_os = "windows")]
+const VP9_HW_DECODERS: &[&str] = &[
+    "vp9_cuvid", // NVIDIA CUVID
+    "vp9_qsv",   // Intel Quick Sync Video
+];
+
+#[cfg(target_os = "windows")]
+const AV1_HW_DECODERS: &[&str] = &[
+    "av1_cuvid", // NVIDIA CUVID
+    "av1_qsv",   // Intel Quick Sync Video
+];
+
 #[cfg(target_os = "linux")]
-const HW_DECODERS: &[&str] = &[
+const H264_HW_DECODERS: &[&str] = &[
     "h264_cuvid", // NVIDIA CUVID
     "h264_vaapi", // Intel/AMD VA-API
     "h264_vdpau", // NVIDIA VDPAU (alternative)
 ];
 
+#[cfg(target_os = "linux")]
+const H265_HW_DECODERS: &[&str] = &[
+    "hevc_cuvid", // NVIDIA CUVID
+    "hevc_vaapi", // Intel/AMD VA-API
+    "hevc_vdpau", // NVIDIA VDPAU (alternative)
+];
+
+#[cfg(target_os = "linux")]
+const VP8_HW_DECODERS: &[&str] = &[
+    "vp8_cuvid", // NVIDIA CUVID
+    "vp8_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const VP9_HW_DECODERS: &[&str] = &[
+    "vp9_cuvid", // NVIDIA CUVID
+    "vp9_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const AV1_HW_DECODERS: &[&str] = &[
+    "av1_cuvid", // NVIDIA CUVID
+    "av1_qsv",   // Intel Quick Sync Video
+];
+
+/// Hardware decoders for a codec, in order of preference.
 #[cfg(not(target_os = "macos"))]
-fn setup_video_decoder(hwaccel: bool) -> Result<ffmpeg::decoder::Video> {
-    let codec = if hwaccel {
-        HW_DECODERS
+fn hw_decoders(codec: VideoCodec) -> &'static [&'static str] {
+    match codec {
+        VideoCodec::H264 => H264_HW_DECODERS,
+        VideoCodec::H265 => H265_HW_DECODERS,
+        VideoCodec::Vp8 => VP8_HW_DECODERS,
+        VideoCodec::Vp9 => VP9_HW_DECODERS,
+        VideoCodec::Av1 => AV1_HW_DECODERS,
+    }
+}
+
+/// FFmpeg's default decoder for a codec, except for AV1 where dav1d or libaom is needed.
+fn find_sw_decoder(codec: VideoCodec) -> Result<ffmpeg::Codec, ClientError> {
+    let decoder = match codec {
+        // the native av1 decoder only works with a hardware device
+        VideoCodec::Av1 => ["libdav1d", "libaom-av1"]
             .iter()
-            .find_map(|&name| {
-                ffmpeg::codec::decoder::find_by_name(name).and_then(|decoder| {
-                    println!("Using hardware decoder: {}", name);
-                    Some(decoder)
-                })
-            })
-            .unwrap_or_else(|| {
-                println!("No hardware decoders found. Falling back to software decoder (h264).");
-                ffmpeg::codec::decoder::find(ffmpeg::codec::Id::H264)
-                    .expect("Default H264 software decoder (h264) not found.")
-            })
+            .find_map(|name| ffmpeg::codec::decoder::find_by_name(name)),
+        _ => ffmpeg::codec::decoder::find(codec.ffmpeg_id()),
+    };
+    decoder.ok_or(ClientError::DecoderNotFound(codec.to_string()))
+}
+
+/// Whether one of the hardware decoders for `codec` decodes the test `packets`.
+#[cfg(not(target_os = "macos"))]
+fn hw_decodes(codec: VideoCodec, packets: &[ffmpeg::Packet]) -> bool {
+    hw_decoders(codec).iter().any(|name| {
+        ffmpeg::codec::decoder::find_by_name(name)
+            .is_some_and(|decoder| try_decoder(open_decoder(decoder), packets).is_ok())
+    })
+}
+
+/// Whether VideoToolbox decodes the test `packets` of `codec`.
+#[cfg(target_os = "macos")]
+fn hw_decodes(codec: VideoCodec, packets: &[ffmpeg::Packet]) -> bool {
+    try_decoder(open_videotoolbox_decoder(codec), packets).is_ok()
+}
+
+/// Codecs that can be decoded here, in order of preference, with the ones that have a
+/// working hardware decoder first when `hwaccel` is set.
+///
+/// Every decoder is opened and given a test frame, as `wireless-display probe` does, since
+/// a decoder in the FFmpeg build may still fail to open or to decode. The results are kept
+/// for the rest of the process.
+fn decodable_codecs(hwaccel: bool) -> Result<Vec<VideoCodec>, ClientError> {
+    static SOFTWARE_CODECS: OnceLock<Vec<VideoCodec>> = OnceLock::new();
+    static HARDWARE_CODECS: OnceLock<Vec<VideoCodec>> = OnceLock::new();
+
+    init_ffmpeg().map_err(ClientError::ffmpeg("Failed to initialize FFmpeg"))?;
+    let cache = if hwaccel {
+        &HARDWARE_CODECS
     } else {
-        ffmpeg::codec::decoder::find(ffmpeg::codec::Id::H264)
-            .ok_or(anyhow::anyhow!("H264 decoder not found"))?
+        &SOFTWARE_CODECS
     };
+    let codecs = cache.get_or_init(|| {
+        let mut hw_codecs = vec![];
+        let mut sw_codecs = vec![];
+        for &codec in VideoCodec::ALL {
+            let packets = test_packets(codec);
+            if hwaccel && hw_decodes(codec, &packets) {
+                hw_codecs.push(codec);
+            } else if find_sw_decoder(codec)
+                .is_ok_and(|decoder| try_decoder(open_decoder(decoder), &packets).is_ok())
+            {
+                sw_codecs.push(codec);
+            } else {
+                debug!(%codec, "No working decoder");
+            }
+        }
+        hw_codecs.extend(sw_codecs);
+        hw_codecs
+    });
+    Ok(codecs.clone())
+}
+
+/// Open a decoder, hardware ones first when `hwaccel` is set, falling back through the
+/// list when one fails to open.
+#[cfg(not(target_os = "macos"))]
+fn setup_video_decoder(
+    codec: VideoCodec,
+    hwaccel: bool,
+) -> Result<ffmpeg::decoder::Video, ClientError> {
+    if hwaccel {
+        for &name in hw_decoders(codec) {
+            let Some(decoder) = ffmpeg::codec::decoder::find_by_name(name) else {
+                continue;
+            };
+            match open_decoder(decoder) {
+                Ok(decoder) => {
+                    info!(decoder = name, "Using hardware decoder");
+                    return Ok(decoder);
+                }
+                Err(err) => {
+                    warn!(decoder = name, error = %err, "Failed to open hardware decoder, trying the next one");
+                }
+            }
+        }
+        warn!(%codec, "No hardware decoder works, falling back to a software decoder");
+    }
+
+    open_decoder(find_sw_decoder(codec)?)
+}
+
+#[cfg(target_os = "macos")]
+fn setup_video_decoder(
+    codec: VideoCodec,
+    hwaccel: bool,
+) -> Result<ffmpeg::decoder::Video, ClientError> {
+    if hwaccel {
+        match open_videotoolbox_decoder(codec) {
+            Ok(decoder) => {
+                info!(decoder = "videotoolbox", %codec, "Using hardware decoder");
+                return Ok(decoder);
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to enable hardware acceleration with videotoolbox");
+            }
+        }
+    }
+
+    open_decoder(find_sw_decoder(codec)?)
+}
 
-    let context = ffmpeg::codec::context::Context::new_with_codec(codec);
-    Ok(context.decoder().video()?)
+fn open_decoder(decoder: ffmpeg::Codec) -> Result<ffmpeg::decoder::Video, ClientError> {
+    let context = ffmpeg::codec::context::Context::new_with_codec(decoder);
+    context
+        .decoder()
+        .video()
+        .map_err(ClientError::ffmpeg("Failed to create video decoder"))
 }
 
 #[cfg(target_os = "macos")]
@@ -218,64 +546,138 @@ unsafe extern "C" fn hardware_decoder_format_callback(
     }
 }
 
+/// The software decoder for `codec` with a VideoToolbox device attached.
 #[cfg(target_os = "macos")]
-fn setup_video_decoder(hwaccel: bool) -> Result<ffmpeg::decoder::Video> {
-    let codec = ffmpeg::codec::decoder::find(ffmpeg::codec::Id::H264)
-        .ok_or(anyhow::anyhow!("H264 decoder not found"))?;
-    let mut context = ffmpeg::codec::context::Context::new_with_codec(codec);
-
-    if hwaccel {
-        unsafe {
-            let ctx_ptr = context.as_mut_ptr();
-
-            let mut hw_device_ctx: *mut ffmpeg::ffi::AVBufferRef = std::ptr::null_mut();
-            let ret = ffmpeg::ffi::av_hwdevice_ctx_create(
-                &mut hw_device_ctx,
-                ffmpeg::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX,
-                std::ptr::null(),
-                std::ptr::null_mut(),
-                0,
-            );
+fn open_videotoolbox_decoder(codec: VideoCodec) -> Result<ffmpeg::decoder::Video, ClientError> {
+    let decoder = find_sw_decoder(codec)?;
+    let mut context = ffmpeg::codec::context::Context::new_with_codec(decoder);
 
-            if ret < 0 {
-                eprintln!("Failed to enable hardware acceleration with videotoolbox.");
-            } else {
-                (*ctx_ptr).hw_device_ctx = hw_device_ctx;
-                (*ctx_ptr).get_format = Some(hardware_decoder_format_callback);
-                println!("Using hardware decoder: h264_videotoolbox");
-            }
+    unsafe {
+        let ctx_ptr = context.as_mut_ptr();
+
+        let mut hw_device_ctx: *mut ffmpeg::ffi::AVBufferRef = std::ptr::null_mut();
+        let ret = ffmpeg::ffi::av_hwdevice_ctx_create(
+            &mut hw_device_ctx,
+            ffmpeg::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VIDEOTOOLBOX,
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            0,
+        );
+        if ret < 0 {
+            return Err(ClientError::ffmpeg("Failed to create VideoToolbox device")(
+                ffmpeg::Error::from(ret),
+            ));
         }
+
+        (*ctx_ptr).hw_device_ctx = hw_device_ctx;
+        (*ctx_ptr).get_format = Some(hardware_decoder_format_callback);
     }
 
-    Ok(context.decoder().video()?)
+    context
+        .decoder()
+        .video()
+        .map_err(ClientError::ffmpeg("Failed to create video decoder"))
+}
+
+/// Decode a test frame with an opened decoder, or just report the error that kept it from
+/// opening.
+fn try_decoder(
+    decoder: Result<ffmpeg::decoder::Video, ClientError>,
+    packets: &[ffmpeg::Packet],
+) -> Result<(), String> {
+    let mut decoder = decoder.map_err(|e| e.to_string())?;
+    // without an encoder for the test frame, opening is all that can be checked
+    if packets.is_empty() {
+        return Ok(());
+    }
+    test_decode(&mut decoder, packets)
+}
+
+/// A frame of `codec` from FFmpeg's default encoder to try decoders on, or nothing when
+/// there is no such encoder.
+fn test_packets(codec: VideoCodec) -> Vec<ffmpeg::Packet> {
+    ffmpeg::codec::encoder::find(codec.ffmpeg_id())
+        .and_then(|encoder| test_encode(encoder, ffmpeg::Dictionary::new()).ok())
+        .unwrap_or_default()
+}
+
+/// Try every hardware decoder and the software decoder of every codec on a frame from
+/// FFmpeg's default encoder, for `wireless-display probe`.
+pub fn probe_decoders() -> Result<Vec<ProbeResult>, ClientError> {
+    init_ffmpeg().map_err(ClientError::ffmpeg("Failed to initialize FFmpeg"))?;
+
+    let mut results = vec![];
+    for &codec in VideoCodec::ALL {
+        let packets = test_packets(codec);
+
+        #[cfg(not(target_os = "macos"))]
+        for &name in hw_decoders(codec) {
+            let result = match ffmpeg::codec::decoder::find_by_name(name) {
+                Some(decoder) => try_decoder(open_decoder(decoder), &packets),
+                None => Err("not in this FFmpeg build".to_string()),
+            };
+            results.push(ProbeResult::new(codec, name, true, result));
+        }
+        #[cfg(target_os = "macos")]
+        results.push(ProbeResult::new(
+            codec,
+            "videotoolbox",
+            true,
+            try_decoder(open_videotoolbox_decoder(codec), &packets),
+        ));
+
+        match find_sw_decoder(codec) {
+            Ok(decoder) => results.push(ProbeResult::new(
+                codec,
+                decoder.name(),
+                false,
+                try_decoder(open_decoder(decoder), &packets),
+            )),
+            Err(err) => results.push(ProbeResult::new(
+                codec,
+                &codec.to_string(),
+                false,
+                Err(err.to_string()),
+            )),
+        }
+    }
+    Ok(results)
 }
 
 async fn run_video_processor(
     mut packet_rx: mpsc::Receiver<WebRTCPacket>,
     frame_tx: mpsc::Sender<StreamFrame>,
     mouse_position: Arc<Mutex<Option<MousePosition>>>,
+    keyframe_tx: mpsc::UnboundedSender<()>,
     hwaccel: bool,
-) -> Result<()> {
-    unsafe {
-        ffmpeg::ffi::av_log_set_level(ffmpeg::ffi::AV_LOG_QUIET);
-    }
-    ffmpeg::init()?;
-
-    let mut decoder = setup_video_decoder(hwaccel)?;
-
-    decoder.set_threading(ffmpeg::threading::Config {
-        kind: ffmpeg::threading::Type::Frame,
-        count: 0,
-    });
-    decoder.set_flags(ffmpeg::codec::flag::Flags::LOW_DELAY);
+) -> Result<(), ClientError> {
+    init_ffmpeg().map_err(ClientError::ffmpeg("Failed to initialize FFmpeg"))?;
 
+    let mut video_decoder: Option<(VideoCodec, ffmpeg::decoder::Video)> = None;
     let mut raw_frame = ffmpeg::frame::Video::empty();
     let mut cpu_frame = ffmpeg::frame::Video::empty();
     let mut rgb_frame = ffmpeg::frame::Video::empty();
     let rtp_time_base = ffmpeg::Rational(1, 90000);
-    let decoder_time_base = decoder.time_base();
 
     while let Some(webrtc_packet) = packet_rx.recv().await {
+        // open the decoder once the codec of the stream is known
+        if video_decoder
+            .as_ref()
+            .is_none_or(|(codec, _)| *codec != webrtc_packet.codec)
+        {
+            let mut decoder = setup_video_decoder(webrtc_packet.codec, hwaccel)?;
+            decoder.set_threading(ffmpeg::threading::Config {
+                kind: ffmpeg::threading::Type::Frame,
+                count: 0,
+            });
+            decoder.set_flags(ffmpeg::codec::flag::Flags::LOW_DELAY);
+            video_decoder = Some((webrtc_packet.codec, decoder));
+        }
+        let Some((_, decoder)) = video_decoder.as_mut() else {
+            continue;
+        };
+        let decoder_time_base = decoder.time_base();
+
         // Set packet data and timestamp
         let mut packet = ffmpeg::packet::Packet::copy(&webrtc_packet.data);
         unsafe {
@@ -288,8 +690,10 @@ async fn run_video_processor(
             packet.set_dts(Some(pts));
         }
 
-        // Send packet to decoder
-        if decoder.send_packet(&packet).is_err() {
+        // Send packet to decoder, asking for a keyframe if it cannot be decoded
+        if let Err(err) = decoder.send_packet(&packet) {
+            debug!(error = %err, "Failed to decode frame");
+            let _ = keyframe_tx.send(());
             continue;
         }
 
@@ -323,9 +727,12 @@ async fn run_video_processor(
                     cpu_frame.width(),
                     cpu_frame.height(),
                     ffmpeg::software::scaling::Flags::FAST_BILINEAR,
-                )?;
+                )
+                .map_err(ClientError::ffmpeg("Failed to create video scaler"))?;
 
-                scaler.run(&cpu_frame, &mut rgb_frame)?;
+                scaler
+                    .run(&cpu_frame, &mut rgb_frame)
+                    .map_err(ClientError::ffmpeg("Failed to convert decoded frame"))?;
 
                 // copy pixel data out while scaler is still alive
                 let width = rgb_frame.width() as usize;
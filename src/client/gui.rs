@@ -1,8 +1,8 @@
 use std::{num::NonZeroU32, sync::Arc};
 
-use anyhow::Result;
 use glutin::surface::GlSurface;
 use tokio::sync::mpsc;
+use tracing::{info, warn};
 use winit::{
     application::ApplicationHandler,
     dpi::LogicalSize,
@@ -16,6 +16,7 @@ use super::{
     StreamFrame,
     renderer::{OpenGLRenderer, setup_opengl_context},
 };
+use crate::error::ClientError;
 
 const WINDOW_INITIAL_SIZE: (u32, u32) = (1280, 720);
 
@@ -85,7 +86,7 @@ impl ApplicationHandler for GuiWindow {
 
         window.request_redraw();
 
-        println!("GUI window created. Press F11 to toggle fullscreen.");
+        info!("GUI window created. Press F11 to toggle fullscreen.");
     }
 
     fn window_event(
@@ -150,7 +151,7 @@ impl ApplicationHandler for GuiWindow {
                     }
 
                     if let Err(err) = gl_surface.swap_buffers(gl_context) {
-                        eprintln!("Failed to swap buffers: {}", err);
+                        warn!(error = %err, "Failed to swap buffers");
                     }
                 }
 
@@ -174,7 +175,8 @@ impl ApplicationHandler for GuiWindow {
     }
 }
 
-pub fn run_gui(frame_rx: mpsc::Receiver<StreamFrame>, cursor_size: u32) -> Result<()> {
+/// Show the stream in a window, blocking the calling thread until it is closed.
+pub fn run_gui(frame_rx: mpsc::Receiver<StreamFrame>, cursor_size: u32) -> Result<(), ClientError> {
     let event_loop = EventLoop::new()?;
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     let mut gui_window = GuiWindow::new(frame_rx, cursor_size);
@@ -1,12 +1,20 @@
-use anyhow::Result;
+use std::{net::SocketAddr, time::Duration};
+
 use tokio::sync::mpsc;
 
+use crate::{
+    error::ClientError,
+    shared::{MousePosition, ShutdownHandle, StreamInfo},
+};
+
 mod connect;
 mod gui;
 mod pair;
 pub(crate) mod renderer;
+mod reorder;
 
-use crate::shared::MousePosition;
+pub use connect::probe_decoders;
+pub use gui::run_gui;
 
 #[derive(Debug, Clone)]
 pub struct StreamFrame {
@@ -16,39 +24,166 @@ pub struct StreamFrame {
     pub mouse: Option<MousePosition>,
 }
 
-pub async fn run_cli_client(
-    code: String,
-    password: Option<String>,
-    hwaccel: bool,
-    cursor_size: u32,
-) -> Result<()> {
-    let _awake = keep_active::Builder::default()
-        .display(true)
-        .reason("Wireless Display Client Running")
-        .app_name("Wireless Display")
-        .app_reverse_domain("com.example.wireless-display")
-        .create()?;
-
-    // find the server address and port using mDNS
-    let server_addr = pair::find_server_address(code)
-        .await?
-        .ok_or(anyhow::anyhow!("Server not found"))?;
-
-    let (frame_tx, frame_rx) = mpsc::channel::<StreamFrame>(2);
-
-    // start the webrtc in a separate task
-    let frame_tx_clone = frame_tx.clone();
-    tokio::spawn(connect::start_webrtc(
-        password,
-        server_addr,
-        hwaccel,
-        frame_tx_clone,
-    ));
-
-    // run GUI in main thread
-    if let Err(err) = gui::run_gui(frame_rx, cursor_size) {
-        eprintln!("GUI error: {}", err);
-    }
-
-    Ok(())
+/// Connection events reported by a running [`Client`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+/// A server found over mDNS, passed to the [`ClientBuilder::confirm_server`] callback.
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+pub(crate) type ServerConfirm = dyn Fn(&DiscoveredServer) -> bool + Send + Sync;
+
+/// Resolved client settings, after merging the config file and command line.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    pub code: String,
+    pub password: Option<String>,
+    pub hwaccel: bool,
+    pub cursor_size: u32,
+    pub server: Option<String>,
+    pub auto_accept: bool,
+    pub discovery_timeout: Option<Duration>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            code: String::from("hello"),
+            password: None,
+            hwaccel: false,
+            cursor_size: 16,
+            server: None,
+            auto_accept: false,
+            discovery_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Builder for a display [`Client`].
+///
+/// Without [`ClientBuilder::server`] the server is discovered over mDNS. Each match is
+/// passed to the [`ClientBuilder::confirm_server`] callback unless `auto_accept` is set,
+/// in which case the first server with a matching pairing code is used.
+#[derive(Default)]
+pub struct ClientBuilder {
+    settings: ClientSettings,
+    confirm: Option<Box<ServerConfirm>>,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_settings(settings: ClientSettings) -> Self {
+        ClientBuilder {
+            settings,
+            confirm: None,
+        }
+    }
+
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.settings.code = code.into();
+        self
+    }
+
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.settings.password = password;
+        self
+    }
+
+    pub fn hwaccel(mut self, hwaccel: bool) -> Self {
+        self.settings.hwaccel = hwaccel;
+        self
+    }
+
+    /// Connect directly to `host:port`, skipping mDNS discovery.
+    pub fn server(mut self, server: impl Into<String>) -> Self {
+        self.settings.server = Some(server.into());
+        self
+    }
+
+    pub fn auto_accept(mut self, auto_accept: bool) -> Self {
+        self.settings.auto_accept = auto_accept;
+        self
+    }
+
+    /// How long to browse for a server, `None` waits forever.
+    pub fn discovery_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.settings.discovery_timeout = timeout;
+        self
+    }
+
+    /// Ask before connecting to a discovered server.
+    pub fn confirm_server(
+        mut self,
+        confirm: impl Fn(&DiscoveredServer) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.confirm = Some(Box::new(confirm));
+        self
+    }
+
+    /// Find the server and negotiate the stream.
+    pub async fn connect(self) -> Result<Client, ClientError> {
+        let ClientBuilder { settings, confirm } = self;
+
+        // connect directly if an address is given, otherwise find the server using mDNS
+        let address = match &settings.server {
+            Some(server) => pair::resolve_server_address(server).await?,
+            None => {
+                let confirm = if settings.auto_accept {
+                    None
+                } else {
+                    confirm.as_deref()
+                };
+                pair::find_server_address(settings.code, confirm, settings.discovery_timeout)
+                    .await?
+            }
+        };
+
+        let shutdown = ShutdownHandle::new();
+        let (frame_tx, frames) = mpsc::channel::<StreamFrame>(2);
+        let (event_tx, events) = mpsc::unbounded_channel::<ClientEvent>();
+
+        let stream = connect::start_webrtc(
+            settings.password,
+            address,
+            settings.hwaccel,
+            frame_tx,
+            event_tx,
+            shutdown.subscribe(),
+        )
+        .await?;
+
+        Ok(Client {
+            address,
+            stream,
+            frames,
+            events,
+            shutdown,
+        })
+    }
+}
+
+/// A client connected to a display server.
+pub struct Client {
+    /// Address of the server the client is connected to.
+    pub address: SocketAddr,
+    /// Codec and encoder the server picked, when the server reports them.
+    pub stream: Option<StreamInfo>,
+    /// Decoded RGBA frames, with the latest mouse position.
+    pub frames: mpsc::Receiver<StreamFrame>,
+    /// Peer connection state changes.
+    pub events: mpsc::UnboundedReceiver<ClientEvent>,
+    /// Closes the peer connection and stops decoding.
+    pub shutdown: ShutdownHandle,
 }
@@ -1,60 +1,146 @@
-use std::{collections::HashSet, net::SocketAddr};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use dialoguer::Confirm;
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
+use tracing::{info, warn};
 
-pub async fn find_server_address(code: String) -> Result<Option<SocketAddr>> {
+use super::{DiscoveredServer, ServerConfirm};
+use crate::error::ClientError;
+
+const DEFAULT_SERVER_PORT: u16 = 8787;
+
+pub async fn find_server_address(
+    code: String,
+    confirm: Option<&ServerConfirm>,
+    timeout: Option<Duration>,
+) -> Result<SocketAddr, ClientError> {
     let mdns = ServiceDaemon::new()?;
 
     let service_type = "_http._tcp.local.";
     let service_name = "wireless-display";
     let receiver = mdns.browse(service_type)?;
-    println!("Browsing for '{}' on the local network...", service_name);
+    info!(
+        service = service_name,
+        "Browsing for server on the local network..."
+    );
 
+    // the timeout counts the time spent waiting for servers, not in the confirmation
+    let mut remaining = timeout;
     let mut visited_servers = HashSet::new();
+    let mut found = None;
 
-    while let Ok(event) = receiver.recv_async().await {
+    while let Some(event) = recv_within(&receiver, &mut remaining).await {
         if let ServiceEvent::ServiceResolved(info) = event {
             if !info.get_fullname().starts_with(service_name) {
                 continue;
             }
             let properties = info.get_properties();
 
-            if let Some(service_code) = properties.get("code") {
-                if service_code.val_str() == code {
-                    // get the port and address
-                    let port = properties
-                        .get("port")
-                        .and_then(|p| p.val_str().parse::<u16>().ok());
-                    let address = info.get_addresses().iter().find(|addr| addr.is_ipv4());
-
-                    if let (Some(port), Some(address)) = (port, address) {
-                        let ip_address = address.to_ip_addr();
-                        if visited_servers.contains(&ip_address) {
-                            continue;
-                        }
-                        visited_servers.insert(ip_address);
-
-                        if Confirm::new()
-                            .with_prompt(format!(
-                                "Found server '{}' at {}. Connect?",
-                                info.get_fullname(),
-                                address
-                            ))
-                            .default(true)
-                            .interact()?
-                        {
-                            mdns.stop_browse(service_type)?;
-                            return Ok(Some(SocketAddr::new(ip_address, port)));
-                        }
+            if let Some(service_code) = properties.get("code")
+                && service_code.val_str() == code
+            {
+                // get the port and address
+                let port = properties
+                    .get("port")
+                    .and_then(|p| p.val_str().parse::<u16>().ok());
+                let address = info.get_addresses().iter().find(|addr| addr.is_ipv4());
+
+                if let (Some(port), Some(address)) = (port, address) {
+                    let ip_address = address.to_ip_addr();
+                    if visited_servers.contains(&ip_address) {
+                        continue;
+                    }
+                    visited_servers.insert(ip_address);
+
+                    let server = DiscoveredServer {
+                        name: info.get_fullname().to_string(),
+                        address: SocketAddr::new(ip_address, port),
+                    };
+
+                    // without a confirmation callback the first match is taken
+                    if confirm.is_none_or(|confirm| confirm(&server)) {
+                        info!(
+                            name = %server.name,
+                            address = %server.address,
+                            "Found server"
+                        );
+                        found = Some(server.address);
+                        break;
                     }
                 }
             }
         }
     }
 
-    mdns.stop_browse(service_type)?;
+    if let Err(err) = mdns.stop_browse(service_type) {
+        warn!(error = %err, "Failed to stop browsing for servers");
+    }
+
+    found.ok_or(ClientError::ServerNotFound { code, timeout })
+}
+
+/// Wait for the next mDNS event, taking the wait off `remaining` when it is limited.
+///
+/// Returns `None` once the time is up or the daemon has stopped.
+async fn recv_within(
+    receiver: &Receiver<ServiceEvent>,
+    remaining: &mut Option<Duration>,
+) -> Option<ServiceEvent> {
+    let Some(left) = *remaining else {
+        return receiver.recv_async().await.ok();
+    };
+
+    let started = Instant::now();
+    let event = tokio::time::timeout(left, receiver.recv_async()).await;
+    *remaining = Some(left.saturating_sub(started.elapsed()));
+    event.ok()?.ok()
+}
+
+/// Resolve an address given by the user, skipping mDNS discovery: an IPv4 address or a
+/// `host:port` name.
+///
+/// The port defaults to the server's default port when omitted. The server only listens
+/// on IPv4, so IPv6 addresses are refused.
+pub async fn resolve_server_address(server: &str) -> Result<SocketAddr, ClientError> {
+    // IP addresses are used as given, before the colons of a bare IPv6 one can be taken
+    // for a port
+    if let Ok(address) = server.parse::<SocketAddr>() {
+        return ipv4_only(server, address);
+    }
+    let ip = server
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(server);
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return ipv4_only(server, SocketAddr::new(ip, DEFAULT_SERVER_PORT));
+    }
+
+    let (host, port) = server
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .unwrap_or((server, DEFAULT_SERVER_PORT));
+    let addresses = tokio::net::lookup_host((host, port))
+        .await
+        .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+        .map_err(|source| ClientError::AddressResolution {
+            address: server.to_string(),
+            source,
+        })?;
 
-    Ok(None)
+    match addresses.iter().find(|addr| addr.is_ipv4()) {
+        Some(address) => Ok(*address),
+        None if !addresses.is_empty() => Err(ClientError::Ipv6Address(server.to_string())),
+        None => Err(ClientError::NoAddress(server.to_string())),
+    }
+}
+
+/// `address`, unless it is an IPv6 one the server cannot be reached at.
+fn ipv4_only(server: &str, address: SocketAddr) -> Result<SocketAddr, ClientError> {
+    match address.ip().to_canonical() {
+        IpAddr::V4(ip) => Ok(SocketAddr::new(IpAddr::V4(ip), address.port())),
+        IpAddr::V6(_) => Err(ClientError::Ipv6Address(server.to_string())),
+    }
 }
@@ -1,6 +1,5 @@
 use std::{ffi::CString, num::NonZeroU32, sync::Arc};
 
-use anyhow::Result;
 use gl::types::*;
 use glutin::{
     config::ConfigTemplateBuilder,
@@ -14,6 +13,8 @@ use winit::{
     window::Window,
 };
 
+use crate::error::ClientError;
+
 const VERTEX_SHADER_SOURCE: &str = r#"
 #version 330 core
 layout (location = 0) in vec2 aPos;
@@ -88,7 +89,7 @@ pub struct OpenGLRenderer {
 }
 
 impl OpenGLRenderer {
-    pub fn new() -> Result<Self> {
+    pub fn new() -> Result<Self, ClientError> {
         unsafe {
             // compile vertex shader
             let vertex_shader = gl::CreateShader(gl::VERTEX_SHADER);
@@ -0,0 +1,172 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use webrtc::rtp::packet::Packet;
+
+/// How long a missing packet is waited for before it is given up as lost.
+const MAX_WAIT: Duration = Duration::from_millis(150);
+/// Packets held back behind a missing one before it is given up as lost.
+const MAX_PENDING: usize = 512;
+
+/// What comes out of the [`ReorderBuffer`], in sequence order.
+pub(super) enum Reordered {
+    Packet(Packet),
+    /// One or more packets never arrived and were skipped.
+    Lost,
+}
+
+/// Puts RTP packets back in order, giving retransmissions of lost packets time to arrive.
+#[derive(Default)]
+pub(super) struct ReorderBuffer {
+    /// Extended sequence number of the next packet to hand out.
+    next: Option<u64>,
+    pending: BTreeMap<u64, Packet>,
+    gap_since: Option<Instant>,
+}
+
+impl ReorderBuffer {
+    pub fn push(&mut self, packet: Packet) {
+        let sequence_number = packet.header.sequence_number;
+        // start far from zero so extending backwards never underflows
+        let next = *self.next.get_or_insert(sequence_number as u64 + (1 << 32));
+
+        let delta = sequence_number.wrapping_sub(next as u16) as i16;
+        let extended = next.wrapping_add_signed(delta as i64);
+        if extended < next {
+            // duplicate, or arrived after it was given up
+            return;
+        }
+        self.pending.insert(extended, packet);
+    }
+
+    /// When the missing packet [`ReorderBuffer::pop`] is waiting for is given up, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.gap_since.map(|gap_since| gap_since + MAX_WAIT)
+    }
+
+    pub fn pop(&mut self) -> Option<Reordered> {
+        let next = self.next?;
+        let (&first, _) = self.pending.first_key_value()?;
+
+        if first == next {
+            self.next = Some(next + 1);
+            self.gap_since = None;
+            return self.pending.remove(&first).map(Reordered::Packet);
+        }
+
+        let gap_since = *self.gap_since.get_or_insert_with(Instant::now);
+        if gap_since.elapsed() >= MAX_WAIT || self.pending.len() > MAX_PENDING {
+            self.next = Some(first);
+            self.gap_since = None;
+            return Some(Reordered::Lost);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(sequence_number: u16) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Sequence numbers of the packets handed out until the buffer waits, with `None`
+    /// for a loss.
+    fn drain(buffer: &mut ReorderBuffer) -> Vec<Option<u16>> {
+        std::iter::from_fn(|| buffer.pop())
+            .map(|reordered| match reordered {
+                Reordered::Packet(packet) => Some(packet.header.sequence_number),
+                Reordered::Lost => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn puts_packets_back_in_order() {
+        let mut buffer = ReorderBuffer::default();
+        buffer.push(packet(10));
+        assert_eq!(drain(&mut buffer), [Some(10)]);
+
+        buffer.push(packet(12));
+        assert!(drain(&mut buffer).is_empty());
+        assert!(buffer.deadline().is_some());
+
+        buffer.push(packet(11));
+        assert_eq!(drain(&mut buffer), [Some(11), Some(12)]);
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn keeps_order_across_sequence_number_wraparound() {
+        let mut buffer = ReorderBuffer::default();
+        buffer.push(packet(65534));
+        buffer.push(packet(0));
+        buffer.push(packet(65535));
+        buffer.push(packet(1));
+
+        assert_eq!(
+            drain(&mut buffer),
+            [Some(65534), Some(65535), Some(0), Some(1)]
+        );
+    }
+
+    #[test]
+    fn drops_duplicates_and_late_packets() {
+        let mut buffer = ReorderBuffer::default();
+        buffer.push(packet(0));
+        buffer.push(packet(1));
+        assert_eq!(drain(&mut buffer), [Some(0), Some(1)]);
+
+        buffer.push(packet(1));
+        buffer.push(packet(65535));
+        assert!(drain(&mut buffer).is_empty());
+
+        buffer.push(packet(2));
+        assert_eq!(drain(&mut buffer), [Some(2)]);
+    }
+
+    #[test]
+    fn gives_up_on_a_missing_packet_after_the_wait() {
+        let mut buffer = ReorderBuffer::default();
+        buffer.push(packet(65535));
+        buffer.push(packet(1));
+        assert_eq!(drain(&mut buffer), [Some(65535)]);
+
+        let deadline = buffer.deadline().unwrap();
+        assert!(deadline > Instant::now());
+        buffer.gap_since = Some(Instant::now() - MAX_WAIT);
+        assert_eq!(drain(&mut buffer), [None, Some(1)]);
+
+        // the lost packet is not handed out when it turns up after all
+        buffer.push(packet(0));
+        assert!(drain(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_a_missing_packet_when_too_many_wait_behind_it() {
+        let mut buffer = ReorderBuffer::default();
+        buffer.push(packet(65000));
+        assert_eq!(drain(&mut buffer), [Some(65000)]);
+
+        for sequence_number in 65002..=65002 + MAX_PENDING as u16 {
+            buffer.push(packet(sequence_number));
+        }
+        let reordered = drain(&mut buffer);
+        assert_eq!(reordered.len(), MAX_PENDING + 2);
+        assert_eq!(reordered[..2], [None, Some(65002)]);
+        assert_eq!(reordered.last(), Some(&Some(65002 + MAX_PENDING as u16)));
+    }
+}
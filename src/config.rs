@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use wireless_display::{
+    CaptureBackend, ClientSettings, EncoderOption, EncoderPreset, FfmpegLogLevel, OutputSize,
+    Region, ScalingAlgorithm, ServerSettings, VideoCodec, VideoSource,
+};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Contents of the TOML config file.
+///
+/// Top level `[server]` and `[client]` tables apply to every launch, while
+/// `[profiles.<name>.server]` and `[profiles.<name>.client]` are layered on top
+/// when the profile is selected with `--profile`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub log: LogOptions,
+    pub server: ServerOptions,
+    pub client: ClientOptions,
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub log: LogOptions,
+    pub server: ServerOptions,
+    pub client: ClientOptions,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Logging options, available on every subcommand.
+#[derive(Args, Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogOptions {
+    #[arg(
+        help = "Log filter, e.g. `debug` or `info,wireless_display::server=debug` [default: $RUST_LOG or info]",
+        long,
+        global = true
+    )]
+    pub log_level: Option<String>,
+    #[arg(
+        help = "Log output format [default: text]",
+        long,
+        value_enum,
+        global = true
+    )]
+    pub log_format: Option<LogFormat>,
+    #[arg(help = "Also write logs to this file", long, global = true)]
+    pub log_file: Option<PathBuf>,
+    #[arg(
+        help = "Forward FFmpeg's own log output at this verbosity (quiet, fatal, error, warning, info, verbose, debug, trace) [default: quiet]",
+        long,
+        global = true
+    )]
+    pub ffmpeg_log_level: Option<FfmpegLogLevel>,
+}
+
+impl LogOptions {
+    /// Overlay `other` on top of `self`, preferring values set in `other`.
+    fn merge(self, other: LogOptions) -> LogOptions {
+        LogOptions {
+            log_level: other.log_level.or(self.log_level),
+            log_format: other.log_format.or(self.log_format),
+            log_file: other.log_file.or(self.log_file),
+            ffmpeg_log_level: other.ffmpeg_log_level.or(self.ffmpeg_log_level),
+        }
+    }
+}
+
+/// Server options shared by the command line and the config file.
+#[derive(Args, Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerOptions {
+    #[arg(help = "Port to listen on [default: 8787]", short, long)]
+    pub port: Option<u16>,
+    #[arg(help = "Capture frame rate [default: 60]", short, long)]
+    pub framerate: Option<u32>,
+    #[arg(help = "Pairing code [default: hello]", short, long)]
+    pub code: Option<String>,
+    #[arg(help = "Password for authentication", long)]
+    pub password: Option<String>,
+    #[arg(
+        help = "Enable hardware acceleration",
+        long,
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub hwaccel: Option<bool>,
+    #[arg(
+        help = "Video codecs in order of preference, comma separated (h264, h265, vp8, vp9, av1) [default: h264,h265,vp8,vp9,av1]",
+        long,
+        value_delimiter = ','
+    )]
+    pub codec: Option<Vec<VideoCodec>>,
+    #[arg(
+        help = "Encoder tuning (latency, balanced, quality, low-bandwidth) [default: balanced]",
+        long
+    )]
+    pub preset: Option<EncoderPreset>,
+    #[arg(
+        help = "FFmpeg encoder to use for its codec, such as h264_nvenc or libsvtav1, which also puts that codec first",
+        long
+    )]
+    pub encoder: Option<String>,
+    #[arg(
+        help = "FFmpeg option as key=value for the encoder given with --encoder, overriding the preset (repeatable)",
+        long = "encoder-opt"
+    )]
+    pub encoder_opt: Option<Vec<EncoderOption>>,
+    #[arg(
+        help = "What to stream: screen, testsrc[:WxH] for a test pattern or file:<path> for a looping video file [default: screen]",
+        long
+    )]
+    pub source: Option<VideoSource>,
+    #[arg(
+        help = "How monitors and regions are captured: ffmpeg (gdigrab, x11grab or avfoundation) or xcap, windows always use xcap [default: ffmpeg]",
+        long
+    )]
+    pub capture_backend: Option<CaptureBackend>,
+    #[arg(
+        help = "X display to capture on Linux, such as :1 for Xvfb [default: $DISPLAY]",
+        long
+    )]
+    pub x11_display: Option<String>,
+    #[arg(
+        help = "Monitor to capture, by index counting from 0, name or regex (see `monitors`) [default: prompt]",
+        short,
+        long
+    )]
+    pub monitor: Option<String>,
+    #[arg(
+        help = "Capture only this rectangle, as x,y,w,h relative to the monitor (or to the desktop when no monitor is given)",
+        long
+    )]
+    pub region: Option<Region>,
+    #[arg(
+        help = "Capture a single window instead of a monitor, by id, title or regex on the title or process (see `windows`)",
+        long,
+        conflicts_with_all = ["monitor", "region"]
+    )]
+    pub window: Option<String>,
+    #[arg(
+        help = "Encode at a smaller size, as WxH to fit into or a scale factor such as 0.5 [default: capture size]",
+        long
+    )]
+    pub output_size: Option<OutputSize>,
+    #[arg(
+        help = "Scaling algorithm (fast-bilinear, bilinear, bicubic, point, area, lanczos, spline) [default: fast-bilinear]",
+        long
+    )]
+    pub scaling: Option<ScalingAlgorithm>,
+    #[arg(
+        help = "Upper limit in kbit/s for the bitrate, which otherwise adapts to the network [default: 20000]",
+        long
+    )]
+    pub max_bitrate: Option<u32>,
+    #[arg(help = "Maximum number of simultaneous viewers [default: 4]", long)]
+    pub max_viewers: Option<usize>,
+    #[arg(
+        help = "Packets kept per viewer to retransmit lost ones, between 64 and 32768 [default: 1024]",
+        long
+    )]
+    pub nack_buffer: Option<u16>,
+    #[arg(
+        help = "Milliseconds between frames sent while the screen does not change, 0 sends every captured frame [default: 1000]",
+        long
+    )]
+    pub idle_heartbeat: Option<u64>,
+}
+
+impl ServerOptions {
+    /// Overlay `other` on top of `self`, preferring values set in `other`.
+    ///
+    /// A window and a monitor or region exclude each other, so choosing one in `other`
+    /// drops the other from `self`.
+    fn merge(mut self, other: ServerOptions) -> ServerOptions {
+        if other.monitor.is_some() || other.region.is_some() {
+            self.window = None;
+        }
+        if other.window.is_some() {
+            self.monitor = None;
+            self.region = None;
+        }
+
+        ServerOptions {
+            port: other.port.or(self.port),
+            framerate: other.framerate.or(self.framerate),
+            code: other.code.or(self.code),
+            password: other.password.or(self.password),
+            hwaccel: other.hwaccel.or(self.hwaccel),
+            codec: other.codec.or(self.codec),
+            preset: other.preset.or(self.preset),
+            encoder: other.encoder.or(self.encoder),
+            encoder_opt: other.encoder_opt.or(self.encoder_opt),
+            source: other.source.or(self.source),
+            capture_backend: other.capture_backend.or(self.capture_backend),
+            x11_display: other.x11_display.or(self.x11_display),
+            monitor: other.monitor.or(self.monitor),
+            region: other.region.or(self.region),
+            window: other.window.or(self.window),
+            output_size: other.output_size.or(self.output_size),
+            scaling: other.scaling.or(self.scaling),
+            max_bitrate: other.max_bitrate.or(self.max_bitrate),
+            max_viewers: other.max_viewers.or(self.max_viewers),
+            nack_buffer: other.nack_buffer.or(self.nack_buffer),
+            idle_heartbeat: other.idle_heartbeat.or(self.idle_heartbeat),
+        }
+    }
+
+    fn resolve(self) -> ServerSettings {
+        let defaults = ServerSettings::default();
+        ServerSettings {
+            port: self.port.unwrap_or(defaults.port),
+            framerate: self.framerate.unwrap_or(defaults.framerate),
+            code: self.code.unwrap_or(defaults.code),
+            password: self.password.or(defaults.password),
+            hwaccel: self.hwaccel.unwrap_or(defaults.hwaccel),
+            codecs: self.codec.unwrap_or(defaults.codecs),
+            preset: self.preset.unwrap_or(defaults.preset),
+            encoder: self.encoder.or(defaults.encoder),
+            encoder_options: self.encoder_opt.unwrap_or(defaults.encoder_options),
+            source: self.source.unwrap_or(defaults.source),
+            capture_backend: self.capture_backend.unwrap_or(defaults.capture_backend),
+            x11_display: self.x11_display.or(defaults.x11_display),
+            monitor: self.monitor.or(defaults.monitor),
+            region: self.region.or(defaults.region),
+            window: self.window.or(defaults.window),
+            output_size: self.output_size.or(defaults.output_size),
+            scaling: self.scaling.unwrap_or(defaults.scaling),
+            max_bitrate: self.max_bitrate.or(defaults.max_bitrate),
+            max_viewers: self.max_viewers.unwrap_or(defaults.max_viewers),
+            nack_buffer: self.nack_buffer.unwrap_or(defaults.nack_buffer),
+            idle_heartbeat: match self.idle_heartbeat {
+                Some(0) => None,
+                Some(millis) => Some(Duration::from_millis(millis)),
+                None => defaults.idle_heartbeat,
+            },
+        }
+    }
+}
+
+/// Client options shared by the command line and the config file.
+#[derive(Args, Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClientOptions {
+    #[arg(help = "Pairing code [default: hello]", short, long)]
+    pub code: Option<String>,
+    #[arg(help = "Password for authentication", long)]
+    pub password: Option<String>,
+    #[arg(
+        help = "Enable hardware acceleration",
+        long,
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub hwaccel: Option<bool>,
+    #[arg(help = "Cursor size [default: 16]", long)]
+    pub cursor_size: Option<u32>,
+    #[arg(
+        help = "Connect directly to host:port instead of discovering over mDNS",
+        long
+    )]
+    pub server: Option<String>,
+    #[arg(
+        help = "Connect to the first discovered server without asking",
+        long,
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub auto_accept: Option<bool>,
+    #[arg(
+        help = "Seconds to wait for server discovery, 0 waits forever [default: 30]",
+        long
+    )]
+    pub discovery_timeout: Option<u64>,
+}
+
+impl ClientOptions {
+    /// Overlay `other` on top of `self`, preferring values set in `other`.
+    fn merge(self, other: ClientOptions) -> ClientOptions {
+        ClientOptions {
+            code: other.code.or(self.code),
+            password: other.password.or(self.password),
+            hwaccel: other.hwaccel.or(self.hwaccel),
+            cursor_size: other.cursor_size.or(self.cursor_size),
+            server: other.server.or(self.server),
+            auto_accept: other.auto_accept.or(self.auto_accept),
+            discovery_timeout: other.discovery_timeout.or(self.discovery_timeout),
+        }
+    }
+
+    fn resolve(self) -> ClientSettings {
+        let defaults = ClientSettings::default();
+        ClientSettings {
+            code: self.code.unwrap_or(defaults.code),
+            password: self.password.or(defaults.password),
+            hwaccel: self.hwaccel.unwrap_or(defaults.hwaccel),
+            cursor_size: self.cursor_size.unwrap_or(defaults.cursor_size),
+            server: self.server.or(defaults.server),
+            auto_accept: self.auto_accept.unwrap_or(defaults.auto_accept),
+            discovery_timeout: match self.discovery_timeout {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => defaults.discovery_timeout,
+            },
+        }
+    }
+}
+
+/// Per-user config file location, e.g. `~/.config/wireless-display/config.toml`.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("wireless-display").join(CONFIG_FILE_NAME))
+}
+
+impl ConfigFile {
+    /// Load the config file from `path`, or from the per-user default location.
+    ///
+    /// An explicitly given path must exist, a missing default file is treated as empty.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+
+        if !required && !path.exists() {
+            return Ok(ConfigFile::default());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>> {
+        match name {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .map(Some)
+                .ok_or_else(|| anyhow::anyhow!("Profile '{}' not found in config file", name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve logging options: `[log]` < profile < command line.
+    pub fn log_options(&self, profile: Option<&str>, cli: LogOptions) -> Result<LogOptions> {
+        let mut options = self.log.clone();
+        if let Some(profile) = self.profile(profile)? {
+            options = options.merge(profile.log.clone());
+        }
+        Ok(options.merge(cli))
+    }
+
+    /// Resolve server settings: defaults < `[server]` < profile < command line.
+    pub fn server_settings(
+        &self,
+        profile: Option<&str>,
+        cli: ServerOptions,
+    ) -> Result<ServerSettings> {
+        let mut options = self.server.clone();
+        if let Some(profile) = self.profile(profile)? {
+            options = options.merge(profile.server.clone());
+        }
+        Ok(options.merge(cli).resolve())
+    }
+
+    /// Resolve client settings: defaults < `[client]` < profile < command line.
+    pub fn client_settings(
+        &self,
+        profile: Option<&str>,
+        cli: ClientOptions,
+    ) -> Result<ClientSettings> {
+        let mut options = self.client.clone();
+        if let Some(profile) = self.profile(profile)? {
+            options = options.merge(profile.client.clone());
+        }
+        Ok(options.merge(cli).resolve())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> ConfigFile {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn empty_config_gives_the_defaults() {
+        let config = config("");
+
+        let server = config
+            .server_settings(None, ServerOptions::default())
+            .unwrap();
+        let defaults = ServerSettings::default();
+        assert_eq!(server.port, defaults.port);
+        assert_eq!(server.framerate, defaults.framerate);
+        assert_eq!(server.code, defaults.code);
+        assert_eq!(server.idle_heartbeat, defaults.idle_heartbeat);
+
+        let client = config
+            .client_settings(None, ClientOptions::default())
+            .unwrap();
+        let defaults = ClientSettings::default();
+        assert_eq!(client.code, defaults.code);
+        assert_eq!(client.discovery_timeout, defaults.discovery_timeout);
+    }
+
+    #[test]
+    fn command_line_overrides_profile_overrides_file() {
+        let config = config(
+            r#"
+            [server]
+            port = 9000
+            framerate = 30
+            code = "file"
+
+            [client]
+            code = "file"
+            cursor-size = 24
+
+            [profiles.demo.server]
+            framerate = 15
+            code = "profile"
+
+            [profiles.demo.client]
+            code = "profile"
+            "#,
+        );
+        let cli = ServerOptions {
+            code: Some("cli".to_string()),
+            ..Default::default()
+        };
+
+        let server = config.server_settings(Some("demo"), cli.clone()).unwrap();
+        assert_eq!(server.port, 9000);
+        assert_eq!(server.framerate, 15);
+        assert_eq!(server.code, "cli");
+
+        // without the profile only the top level table applies
+        let server = config.server_settings(None, cli).unwrap();
+        assert_eq!(server.framerate, 30);
+        assert_eq!(server.code, "cli");
+
+        let client = config
+            .client_settings(Some("demo"), ClientOptions::default())
+            .unwrap();
+        assert_eq!(client.code, "profile");
+        assert_eq!(client.cursor_size, 24);
+    }
+
+    #[test]
+    fn missing_profile_is_an_error() {
+        let config = config("[profiles.demo.server]\nport = 9000");
+
+        let err = config
+            .server_settings(Some("other"), ServerOptions::default())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Profile 'other' not found in config file");
+        assert!(
+            config
+                .client_settings(Some("other"), ClientOptions::default())
+                .is_err()
+        );
+        assert!(
+            config
+                .log_options(Some("other"), LogOptions::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for toml in [
+            "[server]\nprot = 9000",
+            "[client]\nserver-address = \"10.0.0.2\"",
+            "[log]\nlevel = \"debug\"",
+            "[viewer]\ncode = \"hello\"",
+            "[profiles.demo]\nport = 9000",
+        ] {
+            assert!(toml::from_str::<ConfigFile>(toml).is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn zero_disables_idle_heartbeat_and_discovery_timeout() {
+        let config = config(
+            r#"
+            [server]
+            idle-heartbeat = 0
+
+            [client]
+            discovery-timeout = 0
+            "#,
+        );
+        let server = config
+            .server_settings(None, ServerOptions::default())
+            .unwrap();
+        assert_eq!(server.idle_heartbeat, None);
+        let client = config
+            .client_settings(None, ClientOptions::default())
+            .unwrap();
+        assert_eq!(client.discovery_timeout, None);
+
+        let server = config
+            .server_settings(
+                None,
+                ServerOptions {
+                    idle_heartbeat: Some(250),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(server.idle_heartbeat, Some(Duration::from_millis(250)));
+        let client = config
+            .client_settings(
+                None,
+                ClientOptions {
+                    discovery_timeout: Some(5),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(client.discovery_timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn monitor_or_region_replaces_a_window_from_the_file() {
+        let config = config(
+            r#"
+            [server]
+            window = "Grafana"
+
+            [profiles.desk.server]
+            monitor = "1"
+            "#,
+        );
+
+        let server = config
+            .server_settings(
+                None,
+                ServerOptions {
+                    region: Some("0,0,800,600".parse().unwrap()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(server.window, None);
+        assert_eq!(server.region, Some("0,0,800,600".parse().unwrap()));
+
+        let server = config
+            .server_settings(Some("desk"), ServerOptions::default())
+            .unwrap();
+        assert_eq!(server.window, None);
+        assert_eq!(server.monitor.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn window_replaces_a_monitor_and_region_from_the_file() {
+        let config = config(
+            r#"
+            [server]
+            monitor = "Virtual.*"
+            region = "0,0,800,600"
+            "#,
+        );
+
+        let server = config
+            .server_settings(
+                None,
+                ServerOptions {
+                    window: Some("Grafana".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(server.window.as_deref(), Some("Grafana"));
+        assert_eq!(server.monitor, None);
+        assert_eq!(server.region, None);
+
+        // a monitor from the command line keeps the region from the file
+        let server = config
+            .server_settings(
+                None,
+                ServerOptions {
+                    monitor: Some("0".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(server.monitor.as_deref(), Some("0"));
+        assert_eq!(server.region, Some("0,0,800,600".parse().unwrap()));
+    }
+
+    #[test]
+    fn log_options_follow_the_same_layering() {
+        let config = config(
+            r#"
+            [log]
+            log-level = "info"
+            log-format = "json"
+
+            [profiles.debug.log]
+            log-level = "debug"
+            "#,
+        );
+
+        let options = config
+            .log_options(Some("debug"), LogOptions::default())
+            .unwrap();
+        assert_eq!(options.log_level.as_deref(), Some("debug"));
+        assert_eq!(options.log_format, Some(LogFormat::Json));
+
+        let cli = LogOptions {
+            log_format: Some(LogFormat::Text),
+            ..Default::default()
+        };
+        let options = config.log_options(Some("debug"), cli).unwrap();
+        assert_eq!(options.log_format, Some(LogFormat::Text));
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use ffmpeg_next as ffmpeg;
+use thiserror::Error;
+
+use crate::server::Region;
+
+/// Errors returned by the display server.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Failed to enumerate monitors: {0}")]
+    Monitor(#[from] xcap::XCapError),
+    #[error("No monitors found")]
+    NoMonitors,
+    #[error("No monitor matches '{selector}'. Available monitors: {available}")]
+    MonitorNotFound { selector: String, available: String },
+    #[error("Monitor selector '{selector}' is ambiguous, it matches: {matches}")]
+    AmbiguousMonitor { selector: String, matches: String },
+    #[error("Invalid monitor selector '{selector}': {source}")]
+    InvalidMonitorSelector {
+        selector: String,
+        #[source]
+        source: regex::Error,
+    },
+    #[error("Failed to enumerate windows: {0}")]
+    Window(xcap::XCapError),
+    #[error("No windows found")]
+    NoWindows,
+    #[error("No window matches '{selector}'. Available windows: {available}")]
+    WindowNotFound { selector: String, available: String },
+    #[error("Window selector '{selector}' is ambiguous, it matches: {matches}")]
+    AmbiguousWindow { selector: String, matches: String },
+    #[error("Invalid window selector '{selector}': {source}")]
+    InvalidWindowSelector {
+        selector: String,
+        #[source]
+        source: regex::Error,
+    },
+    #[error("No X display to capture, set $DISPLAY or --x11-display")]
+    NoX11Display,
+    #[error("Cannot open X display '{display}': {reason}")]
+    X11Display { display: String, reason: String },
+    #[error("Region {region} does not fit inside {bounds}")]
+    RegionOutOfBounds { region: Region, bounds: Region },
+    #[error("Invalid frame rate {0}, it has to be at least 1")]
+    InvalidFramerate(u32),
+    #[error("Capture error: {0}")]
+    Capture(String),
+    #[error("Encoder not found: {0}")]
+    EncoderNotFound(String),
+    #[error("Encoder '{0}' does not produce a supported codec (h264, h265, vp8, vp9, av1)")]
+    UnsupportedEncoder(String),
+    #[error("Encoder '{encoder}' does not work: {reason}")]
+    EncoderUnusable { encoder: String, reason: String },
+    #[error("Encoder options need --encoder to name the encoder they are for")]
+    EncoderOptionsWithoutEncoder,
+    #[error("{context}: {source}")]
+    Ffmpeg {
+        context: &'static str,
+        #[source]
+        source: ffmpeg::Error,
+    },
+    #[error("WebRTC error: {0}")]
+    WebRtc(#[from] webrtc::Error),
+    #[error("Pairing service error: {0}")]
+    Pairing(#[from] mdns_sd::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl ServerError {
+    /// Wrap an FFmpeg error with a description of the failed step, for use with `map_err`.
+    pub(crate) fn ffmpeg(context: &'static str) -> impl FnOnce(ffmpeg::Error) -> ServerError {
+        move |source| ServerError::Ffmpeg { context, source }
+    }
+}
+
+/// Errors returned by the display client.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Server discovery failed: {0}")]
+    Discovery(#[from] mdns_sd::Error),
+    #[error("No server with code '{code}' found{}", timeout.map(|t| format!(" within {} seconds", t.as_secs())).unwrap_or_default())]
+    ServerNotFound {
+        code: String,
+        timeout: Option<Duration>,
+    },
+    #[error("Failed to resolve server address '{address}': {source}")]
+    AddressResolution {
+        address: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Server address '{0}' did not resolve")]
+    NoAddress(String),
+    #[error("Server address '{0}' is IPv6, but the server only listens on IPv4")]
+    Ipv6Address(String),
+    #[error("Failed to connect to server: {0}")]
+    Signaling(#[from] reqwest::Error),
+    #[error("Server rejected the connection ({status}): {message}")]
+    ServerRejected {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Invalid session description: {0}")]
+    InvalidSdp(String),
+    #[error("WebRTC error: {0}")]
+    WebRtc(#[from] webrtc::Error),
+    #[error("Decoder not found: {0}")]
+    DecoderNotFound(String),
+    #[error("{context}: {source}")]
+    Ffmpeg {
+        context: &'static str,
+        #[source]
+        source: ffmpeg::Error,
+    },
+    #[error("Invalid shader source: {0}")]
+    ShaderSource(#[from] std::ffi::NulError),
+    #[error("Event loop error: {0}")]
+    EventLoop(#[from] winit::error::EventLoopError),
+}
+
+impl ClientError {
+    /// Wrap an FFmpeg error with a description of the failed step, for use with `map_err`.
+    pub(crate) fn ffmpeg(context: &'static str) -> impl FnOnce(ffmpeg::Error) -> ClientError {
+        move |source| ClientError::Ffmpeg { context, source }
+    }
+}
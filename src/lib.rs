@@ -0,0 +1,19 @@
+//! Stream a monitor to another machine over WebRTC, or view such a stream.
+//!
+//! Use [`ServerBuilder`] to embed a display server and [`ClientBuilder`] to embed a viewer.
+
+pub mod client;
+pub mod error;
+pub mod server;
+mod shared;
+
+pub use client::{Client, ClientBuilder, ClientEvent, ClientSettings, StreamFrame};
+pub use error::{ClientError, ServerError};
+pub use server::{
+    CaptureBackend, CaptureDevice, EncoderOption, EncoderPreset, OutputSize, Region,
+    ScalingAlgorithm, Server, ServerBuilder, ServerSettings, VideoSource, WindowInfo,
+};
+pub use shared::{
+    FfmpegLogLevel, MousePosition, ProbeResult, ShutdownHandle, StreamInfo, VideoCodec,
+    set_ffmpeg_log_level,
+};
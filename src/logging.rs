@@ -0,0 +1,52 @@
+use std::{fs::OpenOptions, sync::Mutex};
+
+use anyhow::{Context, Result};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+};
+use wireless_display::set_ffmpeg_log_level;
+
+use crate::config::{LogFormat, LogOptions};
+
+/// Install the global `tracing` subscriber, logging to stderr and optionally to a file.
+pub fn init_logging(options: &LogOptions) -> Result<()> {
+    let filter = match &options.log_level {
+        Some(directives) => EnvFilter::try_new(directives)
+            .with_context(|| format!("Invalid log level '{}'", directives))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let format = options.log_format.unwrap_or_default();
+
+    let mut layers = vec![fmt_layer(format, std::io::stderr, true)];
+    if let Some(path) = &options.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        layers.push(fmt_layer(format, Mutex::new(file), false));
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .context("Failed to initialize logging")?;
+
+    set_ffmpeg_log_level(options.ffmpeg_log_level.unwrap_or_default());
+
+    Ok(())
+}
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
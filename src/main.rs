@@ -1,12 +1,19 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use dialoguer::{Confirm, Select};
+use tracing::error;
+use wireless_display::{
+    ClientBuilder, ClientSettings, ProbeResult, ServerBuilder, ServerSettings,
+    client::{probe_decoders, run_gui},
+    server::{list_capture_devices, list_windows, probe_encoders, select_capture_device},
+};
 
-mod client;
-mod server;
-mod shared;
+mod config;
+mod logging;
 
-use client::run_cli_client;
-use server::run_cli_server;
+use config::{ClientOptions, ConfigFile, LogOptions, ServerOptions};
 
 #[derive(Parser)]
 #[command(
@@ -15,6 +22,16 @@ use server::run_cli_server;
     about = "Use your laptop as a second monitor for your Windows desktop PC over WiFi."
 )]
 struct AppCli {
+    #[arg(
+        help = "Path to the config file [default: <config dir>/wireless-display/config.toml]",
+        long,
+        global = true
+    )]
+    config: Option<PathBuf>,
+    #[arg(help = "Named profile from the config file", long, global = true)]
+    profile: Option<String>,
+    #[command(flatten)]
+    log: LogOptions,
     #[command(subcommand)]
     command: AppCommands,
 }
@@ -22,50 +39,260 @@ struct AppCli {
 #[derive(Subcommand)]
 enum AppCommands {
     #[command(about = "Run as server")]
-    Server {
-        #[arg(help = "Port to listen on", short, long, default_value_t = 8787)]
-        port: u16,
-        #[arg(help = "Capture frame rate", short, long, default_value_t = 60)]
-        framerate: u32,
-        #[arg(help = "Pairing code", short, long, default_value_t = String::from("hello"))]
-        code: String,
-        #[arg(help = "Password for authentication", long)]
-        password: Option<String>,
-        #[arg(help = "Enable hardware acceleration", long, default_value_t = false)]
-        hwaccel: bool,
-    },
+    Server(ServerOptions),
 
     #[command(about = "Run as client")]
-    Client {
-        #[arg(help = "Pairing code", short, long, default_value_t = String::from("hello"))]
-        code: String,
-        #[arg(help = "Password for authentication", long)]
-        password: Option<String>,
-        #[arg(help = "Enable hardware acceleration", long, default_value_t = false)]
-        hwaccel: bool,
-        #[arg(help = "Cursor size", long, default_value_t = 16)]
-        cursor_size: u32,
+    Client(ClientOptions),
+
+    #[command(about = "List monitors available for capture, numbered from 0")]
+    Monitors {
+        #[arg(help = "Output format", long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        #[arg(help = "X display to list on Linux [default: $DISPLAY]", long)]
+        x11_display: Option<String>,
+    },
+
+    #[command(about = "List windows available for capture")]
+    Windows {
+        #[arg(help = "Output format", long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        #[arg(help = "X display to list on Linux [default: $DISPLAY]", long)]
+        x11_display: Option<String>,
+    },
+
+    #[command(about = "Try every encoder and decoder on a test frame and report which ones work")]
+    Probe {
+        #[arg(help = "Output format", long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn main() -> Result<()> {
     let cli = AppCli::parse();
+    let config = ConfigFile::load(cli.config.as_deref())?;
+    let profile = cli.profile.as_deref();
+    logging::init_logging(&config.log_options(profile, cli.log)?)?;
 
     match cli.command {
-        AppCommands::Server {
-            port,
-            framerate,
-            code,
-            password,
-            hwaccel,
-        } => run_cli_server(port, framerate, code, password, hwaccel).await?,
-        AppCommands::Client {
-            code,
-            password,
-            hwaccel,
-            cursor_size,
-        } => run_cli_client(code, password, hwaccel, cursor_size).await?,
+        AppCommands::Server(options) => {
+            let settings = config.server_settings(profile, options)?;
+            if settings.source.is_screen() {
+                set_x11_display(settings.x11_display.as_deref());
+            }
+            runtime()?.block_on(run_cli_server(settings))?
+        }
+        AppCommands::Client(options) => {
+            let settings = config.client_settings(profile, options)?;
+            runtime()?.block_on(run_cli_client(settings))?
+        }
+        AppCommands::Monitors {
+            format,
+            x11_display,
+        } => {
+            set_x11_display(x11_display.as_deref());
+            run_cli_monitors(format)?
+        }
+        AppCommands::Windows {
+            format,
+            x11_display,
+        } => {
+            set_x11_display(x11_display.as_deref());
+            run_cli_windows(format)?
+        }
+        AppCommands::Probe { format } => run_cli_probe(format)?,
+    }
+
+    Ok(())
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Point `$DISPLAY` at the X display to capture on Linux, where there may be several.
+///
+/// The library passes the display to x11grab and the pointer, but xcap only lists and
+/// captures monitors and windows on `$DISPLAY`, which the server builder checks. Other
+/// platforms have a single desktop.
+fn set_x11_display(display: Option<&str>) {
+    #[cfg(target_os = "linux")]
+    if let Some(display) = display {
+        // SAFETY: `main` calls this before starting the async runtime or FFmpeg, while it
+        // is the only thread of the process
+        unsafe {
+            std::env::set_var("DISPLAY", display);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = display;
+}
+
+/// Check that the X display in `$DISPLAY` can be opened on Linux, for a clear error
+/// before listing its monitors and windows.
+fn check_x11_display() -> Result<()> {
+    #[cfg(target_os = "linux")]
+    wireless_display::server::resolve_x11_display(None)?;
+    Ok(())
+}
+
+async fn run_cli_server(settings: ServerSettings) -> Result<()> {
+    // a test pattern, a file, a window, or a region without a monitor, needs no monitor
+    // to be picked
+    if !settings.source.is_screen()
+        || settings.window.is_some()
+        || (settings.monitor.is_none() && settings.region.is_some())
+    {
+        return run_server(ServerBuilder::from_settings(settings)).await;
+    }
+
+    // select screen, prompting when no monitor is given
+    check_x11_display()?;
+    let devices = list_capture_devices()?;
+    let device = match &settings.monitor {
+        Some(selector) => select_capture_device(&devices, selector)?,
+        None => {
+            let device_index = Select::new()
+                .with_prompt("Select the virtual screen to use")
+                .items(
+                    &devices
+                        .iter()
+                        .map(|m| format!("{}. {}", m.index, m))
+                        .collect::<Vec<String>>(),
+                )
+                .default(0)
+                .interact()?;
+            devices[device_index].to_owned()
+        }
+    };
+
+    run_server(ServerBuilder::from_settings(settings).device(device)).await
+}
+
+async fn run_server(builder: ServerBuilder) -> Result<()> {
+    let server = builder.build()?;
+
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        shutdown.shutdown();
+    });
+
+    server.run().await?;
+
+    Ok(())
+}
+
+async fn run_cli_client(settings: ClientSettings) -> Result<()> {
+    let _awake = keep_active::Builder::default()
+        .display(true)
+        .reason("Wireless Display Client Running")
+        .app_name("Wireless Display")
+        .app_reverse_domain("com.example.wireless-display")
+        .create()?;
+
+    let cursor_size = settings.cursor_size;
+    let client = ClientBuilder::from_settings(settings)
+        .confirm_server(|server| {
+            Confirm::new()
+                .with_prompt(format!(
+                    "Found server '{}' at {}. Connect?",
+                    server.name, server.address
+                ))
+                .default(true)
+                .interact()
+                .unwrap_or(false)
+        })
+        .connect()
+        .await?;
+
+    // run GUI in main thread
+    let shutdown = client.shutdown.clone();
+    if let Err(err) = run_gui(client.frames, cursor_size) {
+        error!(error = %err, "GUI error");
+    }
+    shutdown.shutdown();
+
+    Ok(())
+}
+
+fn run_cli_monitors(format: OutputFormat) -> Result<()> {
+    check_x11_display()?;
+    let devices = list_capture_devices()?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&devices)?),
+        OutputFormat::Text => {
+            for m in &devices {
+                println!(
+                    "{}. {} ({}x{} at {},{})",
+                    m.index, m.name, m.width, m.height, m.x, m.y
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_cli_windows(format: OutputFormat) -> Result<()> {
+    check_x11_display()?;
+    let windows = list_windows()?;
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&windows)?),
+        OutputFormat::Text => {
+            for w in &windows {
+                println!(
+                    "{}. {} [{}, pid {}] ({}x{} at {},{})",
+                    w.id, w.title, w.app_name, w.pid, w.width, w.height, w.x, w.y
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_cli_probe(format: OutputFormat) -> Result<()> {
+    let encoders = probe_encoders()?;
+    let decoders = probe_decoders()?;
+
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "encoders": encoders,
+                "decoders": decoders,
+            }))?
+        ),
+        OutputFormat::Text => {
+            let print_results = |title: &str, results: &[ProbeResult]| {
+                println!("{}:", title);
+                for r in results {
+                    println!(
+                        "  {:<5} {:<18} {:<9} {}",
+                        r.codec.to_string(),
+                        r.name,
+                        if r.hardware { "hardware" } else { "software" },
+                        match &r.error {
+                            Some(error) => format!("failed: {}", error),
+                            None => "ok".to_string(),
+                        }
+                    );
+                }
+            };
+            print_results("Encoders", &encoders);
+            println!();
+            print_results("Decoders", &decoders);
+        }
     }
 
     Ok(())
@@ -0,0 +1,217 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next as ffmpeg;
+use serde::Deserialize;
+use tracing::info;
+use xcap::Monitor;
+
+use super::{
+    AppState,
+    capture::{Region, elapsed_pts},
+};
+use crate::error::ServerError;
+
+/// How the screen is captured.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureBackend {
+    /// The platform's FFmpeg input device: gdigrab, x11grab or avfoundation.
+    #[default]
+    Ffmpeg,
+    /// Screenshots of the monitor taken with xcap.
+    Xcap,
+}
+
+impl Display for CaptureBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureBackend::Ffmpeg => write!(f, "ffmpeg"),
+            CaptureBackend::Xcap => write!(f, "xcap"),
+        }
+    }
+}
+
+impl FromStr for CaptureBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ffmpeg" => Ok(CaptureBackend::Ffmpeg),
+            "xcap" => Ok(CaptureBackend::Xcap),
+            _ => Err(format!(
+                "invalid capture backend '{}', expected one of: ffmpeg, xcap",
+                s
+            )),
+        }
+    }
+}
+
+/// Something that produces the frames to stream.
+///
+/// Frames may come in any pixel format and change size between two calls. Their pts is
+/// the time they were captured at, in the encoder time base.
+pub(super) trait CaptureSource {
+    /// Wait for the next frame, or `None` once the source has ended.
+    fn next_frame(&mut self) -> Result<Option<&mut ffmpeg::frame::Video>, ServerError>;
+
+    /// Let the next frame go by without capturing it, while nobody watches.
+    ///
+    /// Returns `false` for sources that have to be read anyway.
+    fn skip_frame(&mut self) -> bool {
+        false
+    }
+}
+
+/// Captures a monitor or region with xcap's screenshots, at the configured frame rate.
+pub(super) struct XcapCapture {
+    monitor: Monitor,
+    name: String,
+    /// Part of the monitor to capture, relative to its top left corner, or `None` for all
+    /// of it.
+    region: Option<Region>,
+    pacer: FramePacer,
+    start: Instant,
+    frame: ffmpeg::frame::Video,
+}
+
+impl XcapCapture {
+    pub fn open(state: &AppState) -> Result<Self, ServerError> {
+        let device = &state.device;
+        // the indices change when monitors are plugged in or reordered, their name and
+        // place on the desktop don't
+        let monitor = Monitor::all()?
+            .into_iter()
+            .find(|monitor| {
+                monitor.name().unwrap_or("Unknown".to_string()) == device.name
+                    && monitor.x().unwrap_or_default() == device.x
+                    && monitor.y().unwrap_or_default() == device.y
+                    && monitor.width().unwrap_or_default() == device.width
+                    && monitor.height().unwrap_or_default() == device.height
+            })
+            .ok_or_else(|| {
+                ServerError::Capture(format!(
+                    "Monitor '{}' at {} is gone or has changed",
+                    device.name,
+                    device.bounds()
+                ))
+            })?;
+
+        // xcap captures one monitor at a time
+        let region = state.region();
+        if !device.bounds().contains(&region) {
+            return Err(ServerError::RegionOutOfBounds {
+                region,
+                bounds: device.bounds(),
+            });
+        }
+
+        info!(
+            monitor = %device,
+            region = %region,
+            backend = %CaptureBackend::Xcap,
+            "Starting capture"
+        );
+
+        Ok(XcapCapture {
+            monitor,
+            name: device.name.clone(),
+            region: (region != device.bounds()).then(|| region.offset(-device.x, -device.y)),
+            pacer: FramePacer::new(state.framerate),
+            start: Instant::now(),
+            frame: ffmpeg::frame::Video::empty(),
+        })
+    }
+}
+
+impl CaptureSource for XcapCapture {
+    fn next_frame(&mut self) -> Result<Option<&mut ffmpeg::frame::Video>, ServerError> {
+        self.pacer.wait();
+
+        let image = match self.region {
+            Some(region) => self.monitor.capture_region(
+                region.x as u32,
+                region.y as u32,
+                region.width,
+                region.height,
+            ),
+            None => self.monitor.capture_image(),
+        }
+        .map_err(|e| {
+            ServerError::Capture(format!("Failed to capture monitor '{}': {}", self.name, e))
+        })?;
+        let pts = elapsed_pts(self.start);
+
+        let (width, height) = (image.width(), image.height());
+        if self.frame.width() != width || self.frame.height() != height {
+            self.frame = ffmpeg::frame::Video::new(ffmpeg::format::Pixel::RGBA, width, height);
+        }
+        copy_rgba(image.as_raw(), width, &mut self.frame);
+        self.frame.set_pts(Some(pts));
+
+        Ok(Some(&mut self.frame))
+    }
+
+    fn skip_frame(&mut self) -> bool {
+        self.pacer.wait();
+        true
+    }
+}
+
+/// Paces sources that take screenshots at the configured frame rate.
+pub(super) struct FramePacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new(framerate: u32) -> Self {
+        FramePacer {
+            frame_duration: Duration::from_secs_f64(1.0 / framerate as f64),
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Sleep until it is time for the next frame, keeping the frame rate without drifting.
+    pub fn wait(&mut self) {
+        self.next_frame += self.frame_duration;
+        if let Some(wait) = self.next_frame.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Copy an RGBA image `width` pixels wide into an RGBA frame of the same size, row by
+/// row as the frame may be padded.
+pub(super) fn copy_rgba(pixels: &[u8], width: u32, frame: &mut ffmpeg::frame::Video) {
+    let row_size = width as usize * 4;
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for (row, pixels) in pixels.chunks_exact(row_size).enumerate() {
+        data[row * stride..row * stride + row_size].copy_from_slice(pixels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_capture_backends() {
+        for backend in [CaptureBackend::Ffmpeg, CaptureBackend::Xcap] {
+            assert_eq!(backend.to_string().parse(), Ok(backend));
+        }
+        assert_eq!("XCap".parse(), Ok(CaptureBackend::Xcap));
+    }
+
+    #[test]
+    fn rejects_unknown_capture_backends() {
+        for s in ["", "gdigrab", "x11grab", "xcap "] {
+            let err = s.parse::<CaptureBackend>().unwrap_err();
+            assert!(err.starts_with("invalid capture backend"), "{}", err);
+        }
+    }
+}
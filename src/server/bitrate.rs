@@ -0,0 +1,260 @@
+use std::time::{Duration, Instant};
+
+use webrtc::rtcp::{
+    packet::Packet,
+    payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    receiver_report::ReceiverReport,
+    transport_feedbacks::transport_layer_cc::{PacketStatusChunk, SymbolTypeTcc, TransportLayerCc},
+};
+
+/// Lowest bitrate the estimate may drop to, in bits per second.
+pub(crate) const MIN_BITRATE: u64 = 300_000;
+/// Upper bound for the estimate when no `--max-bitrate` is set.
+pub(crate) const DEFAULT_MAX_BITRATE: u64 = 20_000_000;
+/// Bitrate a new viewer starts at before any feedback arrives.
+pub(crate) const START_BITRATE: u64 = 8_000_000;
+
+/// Loss above this fraction lowers the estimate.
+const HIGH_LOSS: f64 = 0.10;
+/// Loss below this fraction lets the estimate grow.
+const LOW_LOSS: f64 = 0.02;
+/// Growth per increase step while the link is clean.
+const INCREASE_FACTOR: f64 = 1.08;
+/// Minimum time between two increase steps.
+const INCREASE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Bandwidth estimate for one viewer, driven by the RTCP feedback it sends.
+///
+/// Packet loss from receiver reports and transport-wide congestion control feedback
+/// moves the estimate down or up, and REMB caps it at what the receiver asks for.
+pub(crate) struct BandwidthEstimator {
+    estimate: u64,
+    max_bitrate: u64,
+    remb: Option<u64>,
+    last_increase: Instant,
+}
+
+impl BandwidthEstimator {
+    pub fn new(max_bitrate: u64) -> Self {
+        BandwidthEstimator {
+            estimate: START_BITRATE.min(max_bitrate),
+            max_bitrate,
+            remb: None,
+            last_increase: Instant::now(),
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        self.estimate
+    }
+
+    /// Feed one batch of RTCP packets, returning whether the estimate changed.
+    pub fn on_rtcp(&mut self, packets: &[Box<dyn Packet + Send + Sync>]) -> bool {
+        let previous = self.estimate;
+
+        for packet in packets {
+            let packet = packet.as_any();
+            if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                for report in &rr.reports {
+                    self.on_loss(report.fraction_lost as f64 / 256.0);
+                }
+            } else if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                self.remb = Some(remb.bitrate as u64);
+            } else if let Some(twcc) = packet.downcast_ref::<TransportLayerCc>()
+                && let Some(loss) = twcc_loss(twcc)
+            {
+                self.on_loss(loss);
+            }
+        }
+
+        let ceiling = self
+            .remb
+            .map_or(self.max_bitrate, |remb| remb.min(self.max_bitrate));
+        self.estimate = self.estimate.clamp(MIN_BITRATE, ceiling.max(MIN_BITRATE));
+
+        self.estimate != previous
+    }
+
+    fn on_loss(&mut self, loss: f64) {
+        if loss > HIGH_LOSS {
+            self.estimate = (self.estimate as f64 * (1.0 - 0.5 * loss)) as u64;
+        } else if loss < LOW_LOSS && self.last_increase.elapsed() >= INCREASE_INTERVAL {
+            self.estimate = (self.estimate as f64 * INCREASE_FACTOR) as u64;
+            self.last_increase = Instant::now();
+        }
+    }
+}
+
+/// Fraction of packets reported as lost in a transport-wide feedback packet.
+fn twcc_loss(twcc: &TransportLayerCc) -> Option<f64> {
+    let total = twcc.packet_status_count as usize;
+    if total == 0 {
+        return None;
+    }
+
+    let mut counted = 0;
+    let mut lost = 0;
+    for chunk in &twcc.packet_chunks {
+        match chunk {
+            PacketStatusChunk::RunLengthChunk(run) => {
+                let count = (run.run_length as usize).min(total - counted);
+                if run.packet_status_symbol == SymbolTypeTcc::PacketNotReceived {
+                    lost += count;
+                }
+                counted += count;
+            }
+            PacketStatusChunk::StatusVectorChunk(vector) => {
+                // the last vector may be padded past the status count
+                for symbol in vector.symbol_list.iter().take(total - counted) {
+                    if *symbol == SymbolTypeTcc::PacketNotReceived {
+                        lost += 1;
+                    }
+                    counted += 1;
+                }
+            }
+        }
+        if counted >= total {
+            break;
+        }
+    }
+
+    Some(lost as f64 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::{
+        reception_report::ReceptionReport,
+        transport_feedbacks::transport_layer_cc::{
+            RunLengthChunk, StatusChunkTypeTcc, StatusVectorChunk, SymbolSizeTypeTcc,
+        },
+    };
+
+    use super::*;
+
+    fn receiver_report(fraction_lost: u8) -> Box<dyn Packet + Send + Sync> {
+        Box::new(ReceiverReport {
+            reports: vec![ReceptionReport {
+                fraction_lost,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    }
+
+    fn remb(bitrate: f32) -> Box<dyn Packet + Send + Sync> {
+        Box::new(ReceiverEstimatedMaximumBitrate {
+            bitrate,
+            ..Default::default()
+        })
+    }
+
+    fn run(symbol: SymbolTypeTcc, run_length: u16) -> PacketStatusChunk {
+        PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+            type_tcc: StatusChunkTypeTcc::RunLengthChunk,
+            packet_status_symbol: symbol,
+            run_length,
+        })
+    }
+
+    fn twcc(packet_status_count: u16, packet_chunks: Vec<PacketStatusChunk>) -> TransportLayerCc {
+        TransportLayerCc {
+            packet_status_count,
+            packet_chunks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn twcc_loss_counts_run_length_chunks() {
+        let feedback = twcc(
+            10,
+            vec![
+                run(SymbolTypeTcc::PacketReceivedSmallDelta, 6),
+                run(SymbolTypeTcc::PacketNotReceived, 3),
+                run(SymbolTypeTcc::PacketReceivedLargeDelta, 1),
+            ],
+        );
+        assert_eq!(twcc_loss(&feedback), Some(0.3));
+    }
+
+    #[test]
+    fn twcc_loss_ignores_padding_past_the_status_count() {
+        let received = SymbolTypeTcc::PacketReceivedSmallDelta;
+        let lost = SymbolTypeTcc::PacketNotReceived;
+        let feedback = twcc(
+            4,
+            vec![
+                run(received, 2),
+                PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+                    type_tcc: StatusChunkTypeTcc::StatusVectorChunk,
+                    symbol_size: SymbolSizeTypeTcc::TwoBit,
+                    symbol_list: vec![lost, received, lost, lost, lost, lost, lost],
+                }),
+            ],
+        );
+        assert_eq!(twcc_loss(&feedback), Some(0.25));
+
+        // a run longer than the count is cut at the count too
+        let feedback = twcc(4, vec![run(received, 2), run(lost, 100)]);
+        assert_eq!(twcc_loss(&feedback), Some(0.5));
+    }
+
+    #[test]
+    fn twcc_loss_needs_packets() {
+        assert_eq!(twcc_loss(&twcc(0, vec![])), None);
+    }
+
+    #[test]
+    fn high_loss_lowers_the_estimate() {
+        let mut estimator = BandwidthEstimator::new(DEFAULT_MAX_BITRATE);
+
+        // a quarter lost takes off an eighth
+        assert!(estimator.on_rtcp(&[receiver_report(64)]));
+        assert_eq!(estimator.estimate(), 7_000_000);
+
+        // loss between the thresholds keeps the estimate
+        assert!(!estimator.on_rtcp(&[receiver_report(13)]));
+        assert_eq!(estimator.estimate(), 7_000_000);
+    }
+
+    #[test]
+    fn low_loss_raises_the_estimate_at_most_every_interval() {
+        let mut estimator = BandwidthEstimator::new(DEFAULT_MAX_BITRATE);
+        assert!(!estimator.on_rtcp(&[receiver_report(0)]));
+
+        estimator.last_increase -= INCREASE_INTERVAL;
+        assert!(estimator.on_rtcp(&[receiver_report(0)]));
+        assert_eq!(estimator.estimate(), 8_640_000);
+        assert!(!estimator.on_rtcp(&[receiver_report(0)]));
+    }
+
+    #[test]
+    fn estimate_stays_between_the_limits() {
+        let mut estimator = BandwidthEstimator::new(10_000_000);
+        for _ in 0..20 {
+            estimator.on_rtcp(&[receiver_report(255)]);
+        }
+        assert_eq!(estimator.estimate(), MIN_BITRATE);
+
+        let mut estimator = BandwidthEstimator::new(START_BITRATE);
+        estimator.last_increase -= INCREASE_INTERVAL;
+        assert!(!estimator.on_rtcp(&[receiver_report(0)]));
+        assert_eq!(estimator.estimate(), START_BITRATE);
+    }
+
+    #[test]
+    fn remb_caps_the_estimate() {
+        let mut estimator = BandwidthEstimator::new(DEFAULT_MAX_BITRATE);
+        assert!(estimator.on_rtcp(&[remb(2_000_000.0)]));
+        assert_eq!(estimator.estimate(), 2_000_000);
+
+        // the cap stays until the receiver raises it
+        estimator.last_increase -= INCREASE_INTERVAL;
+        estimator.on_rtcp(&[receiver_report(0)]);
+        assert_eq!(estimator.estimate(), 2_000_000);
+
+        estimator.on_rtcp(&[remb(100_000.0)]);
+        assert_eq!(estimator.estimate(), MIN_BITRATE);
+    }
+}
@@ -1,24 +1,50 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
+    str::FromStr,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use ffmpeg_next as ffmpeg;
+use ffmpeg_next::{self as ffmpeg, Rescale};
+#[cfg(not(target_os = "linux"))]
 use mouse_position::mouse_position::Mouse;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, mpsc};
-use webrtc::media::Sample;
+use tracing::{debug, error, info, warn};
+use webrtc::{
+    rtp::{
+        packetizer::{Packetizer, new_packetizer},
+        sequence::new_random_sequencer,
+    },
+    track::track_local::TrackLocalWriter,
+};
+
+use crate::{
+    error::ServerError,
+    shared::{MousePosition, init_ffmpeg},
+};
 
-use crate::shared::MousePosition;
+#[cfg(target_os = "linux")]
+use super::x11::X11Pointer;
+use super::{
+    AppState,
+    backend::{CaptureBackend, CaptureSource, XcapCapture},
+    change::{ChangeDetector, frame_rows},
+    encoder::{ENCODER_TIME_BASE, EncodedFrame, EncoderSet, StreamFormat},
+    source::{self, VideoSource},
+    window::WindowCapture,
+};
 
-use super::AppState;
+/// Largest RTP packet sent, leaving room for the UDP, DTLS and SRTP overhead.
+const RTP_MTU: usize = 1200;
+/// Clock rate of the RTP timestamps, the same for every video codec.
+const RTP_CLOCK_RATE: u32 = 90000;
 
-#[derive(Clone)]
-#[allow(dead_code)]
+#[derive(Clone, Debug, Serialize)]
 pub struct CaptureDevice {
     pub index: usize,
     pub name: String,
@@ -34,253 +60,454 @@ impl Display for CaptureDevice {
     }
 }
 
-#[cfg(target_os = "windows")]
-const HW_ENCODERS: &[&str] = &[
-    "h264_nvenc", // NVIDIA NVENC
-    "h264_amf",   // AMD AMF
-    "h264_qsv",   // Intel Quick Sync Video
-    "h264_mf",    // Microsoft Media Foundation
-];
+impl CaptureDevice {
+    /// The whole monitor, in desktop coordinates.
+    pub fn bounds(&self) -> Region {
+        Region {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
 
-#[cfg(target_os = "macos")]
-const HW_ENCODERS: &[&str] = &[
-    "h264_videotoolbox", // Apple VideoToolbox
-];
+/// A rectangle to capture, written as `x,y,w,h`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
 
-#[cfg(target_os = "linux")]
-const HW_ENCODERS: &[&str] = &[
-    "h264_nvenc", // NVIDIA NVENC
-    "h264_vaapi", // Intel/AMD VA-API
-];
+impl Region {
+    /// Move the region by the origin of the rectangle it is relative to.
+    pub fn offset(self, x: i32, y: i32) -> Region {
+        Region {
+            x: self.x + x,
+            y: self.y + y,
+            ..self
+        }
+    }
+
+    pub fn contains(&self, other: &Region) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x as i64 + other.width as i64 <= self.x as i64 + self.width as i64
+            && other.y as i64 + other.height as i64 <= self.y as i64 + self.height as i64
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} at {},{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid region '{}', expected x,y,w,h", s);
+        let parts = s.split(',').map(str::trim).collect::<Vec<&str>>();
+        let [x, y, width, height] = parts.as_slice() else {
+            return Err(invalid());
+        };
+
+        let region = Region {
+            x: x.parse().map_err(|_| invalid())?,
+            y: y.parse().map_err(|_| invalid())?,
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+        };
+        if region.width == 0 || region.height == 0 {
+            return Err(format!(
+                "invalid region '{}', width and height must be positive",
+                s
+            ));
+        }
+
+        Ok(region)
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
 
 pub async fn capture_screen(
     state: Arc<AppState>,
-    hwaccel: bool,
     mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<()> {
-    let (tx, mut rx) = mpsc::channel::<Sample>(2);
+) -> Result<(), ServerError> {
+    let (tx, mut rx) = mpsc::channel::<EncodedFrame>(2);
     let state_clone = state.clone();
 
     let shutdown_signal = Arc::new(AtomicBool::new(false));
     let shutdown_signal_clone = shutdown_signal.clone();
 
     let send_task = tokio::spawn(async move {
-        while !shutdown_signal_clone.load(Ordering::Relaxed) {
-            if let Some(sample) = rx.recv().await {
-                if let Some(video_track) = state_clone.video_track.lock().await.as_mut() {
-                    if let Err(err) = video_track.write_sample(&sample).await {
-                        eprintln!("Error writing sample: {}", err);
-                        continue;
+        // each frame is split into RTP packets once and written to the tracks of every viewer
+        // using its format, each track fills in the SSRC and payload type of its own connection
+        let mut packetizers: HashMap<StreamFormat, (Box<dyn Packetizer + Send + Sync>, u32)> =
+            HashMap::new();
+
+        // ends once the capture task has stopped and dropped the sender
+        while let Some(EncodedFrame {
+            format,
+            timestamp,
+            sample,
+        }) = rx.recv().await
+        {
+            if shutdown_signal_clone.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let (packetizer, last_timestamp) = packetizers.entry(format).or_insert_with(|| {
+                let packetizer = new_packetizer(
+                    RTP_MTU,
+                    0,
+                    0,
+                    format.codec.payloader(),
+                    Box::new(new_random_sequencer()),
+                    RTP_CLOCK_RATE,
+                );
+                (Box::new(packetizer), timestamp)
+            });
+
+            // move the RTP clock to the frame's capture time, so frames that were late
+            // or dropped keep their place on the client's timeline
+            packetizer.skip_samples(timestamp.wrapping_sub(*last_timestamp));
+            *last_timestamp = timestamp;
+            let packets = match packetizer.packetize(&sample.data, 0) {
+                Ok(packets) => packets,
+                Err(err) => {
+                    warn!(error = %err, "Error packetizing sample");
+                    continue;
+                }
+            };
+
+            for video_track in state_clone.video_tracks(format).await {
+                for packet in &packets {
+                    if let Err(err) = video_track.write_rtp(packet).await {
+                        warn!(error = %err, "Error writing RTP packet");
+                        break;
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok::<(), ServerError>(())
     });
 
     let shutdown_signal_clone = shutdown_signal.clone();
     let capture_task = tokio::task::spawn_blocking(move || {
-        unsafe {
-            ffmpeg::ffi::av_log_set_level(ffmpeg::ffi::AV_LOG_QUIET);
+        init_ffmpeg().map_err(ServerError::ffmpeg("Failed to initialize FFmpeg"))?;
+
+        // windows are always captured with xcap, test patterns and files read through FFmpeg
+        let mut capture: Box<dyn CaptureSource + '_> = match &state.window {
+            Some(window) => Box::new(WindowCapture::open(&state, window)?),
+            None if state.capture_backend == CaptureBackend::Xcap && state.source.is_screen() => {
+                Box::new(XcapCapture::open(&state)?)
+            }
+            None => Box::new(FfmpegCapture::open(&state)?),
+        };
+        capture_frames(&state, capture.as_mut(), &tx, &shutdown_signal_clone)
+    });
+
+    tokio::select! {
+        capture_result = capture_task => {
+            capture_result?.inspect_err(|e| error!(error = %e, "Screen capture failed"))
+        }
+        send_result = send_task => {
+            send_result?
+        }
+        _ = shutdown_rx.recv() => {
+            info!("Shutting down screen capture...");
+            shutdown_signal.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Scale and encode the frames of `source` until it ends or the capture is shut down.
+///
+/// The scaler and encoders are rebuilt whenever the size or pixel format of the frames
+/// changes.
+fn capture_frames(
+    state: &AppState,
+    source: &mut dyn CaptureSource,
+    tx: &mpsc::Sender<EncodedFrame>,
+    shutdown_signal: &AtomicBool,
+) -> Result<(), ServerError> {
+    let mut scaler: Option<ffmpeg::software::scaling::Context> = None;
+    let mut encoders: Option<EncoderSet> = None;
+    let mut scaled_frame = ffmpeg::frame::Video::empty();
+    let mut change_detector = ChangeDetector::new();
+
+    while !shutdown_signal.load(Ordering::Relaxed) {
+        // sources that can wait are not captured while nobody watches
+        if let Some(encoders) = encoders.as_mut()
+            && encoders.is_idle(state)?
+            && source.skip_frame()
+        {
+            continue;
+        }
+
+        let Some(frame) = source.next_frame()? else {
+            break;
+        };
+
+        let changed_input = scaler.as_ref().is_none_or(|scaler| {
+            let input = scaler.input();
+            input.format != frame.format()
+                || input.width != frame.width()
+                || input.height != frame.height()
+        });
+        if changed_input {
+            let (output_width, output_height) =
+                output_dimensions(state, frame.width(), frame.height());
+            scaler = Some(
+                ffmpeg::software::scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    ffmpeg::format::Pixel::YUV420P,
+                    output_width,
+                    output_height,
+                    state.scaling.as_flags(),
+                )
+                .map_err(ServerError::ffmpeg("Failed to create video scaler"))?,
+            );
+            encoders = Some(EncoderSet::new(output_width, output_height));
+            state.set_stream_size(output_width, output_height);
+            scaled_frame = ffmpeg::frame::Video::empty();
+            info!(
+                width = output_width,
+                height = output_height,
+                "Encoding capture"
+            );
+        }
+
+        let (Some(scaler), Some(encoders)) = (scaler.as_mut(), encoders.as_mut()) else {
+            continue;
+        };
+
+        if encoders.skip_frame(state, || change_detector.changed(frame_rows(frame)))? {
+            continue;
         }
-        ffmpeg::init().map_err(|e| anyhow::anyhow!("Failed to initialize FFmpeg: {}", e))?;
 
+        // scale to YUV format
+        scaler
+            .run(frame, &mut scaled_frame)
+            .map_err(ServerError::ffmpeg("Failed to scale captured frame"))?;
+        scaled_frame.set_pts(frame.pts());
+
+        // encode with the selected codec
+        encoders.encode(&mut scaled_frame, state, tx)?;
+    }
+
+    Ok(())
+}
+
+/// Captures a monitor or region with the platform's FFmpeg input device, or plays a test
+/// pattern or video file.
+struct FfmpegCapture {
+    input: ffmpeg::format::context::Input,
+    stream_index: usize,
+    time_base: ffmpeg::Rational,
+    decoder: ffmpeg::decoder::Video,
+    crop: Option<Crop>,
+    decoded_frame: ffmpeg::frame::Video,
+    /// Test patterns and files are read as fast as they decode, so they are played at
+    /// the pace of their timestamps.
+    paced: bool,
+    /// Whether the input starts over when it ends.
+    looped: bool,
+    start: Instant,
+    /// Capture time of the first frame, which the pts of the stream count from.
+    first_timestamp: Option<i64>,
+    /// A looping file starts over after the pts it ended at.
+    pts_offset: i64,
+    last_pts: i64,
+    frame_duration: i64,
+}
+
+impl FfmpegCapture {
+    fn open(state: &AppState) -> Result<Self, ServerError> {
         // create input context
-        let ictx = create_input_context(&state.device, state.framerate).map_err(|e| {
-            eprintln!("Failed to create input context: {}", e);
-            anyhow::anyhow!("Failed to create input context: {}", e)
+        let region = state.region();
+        let input = match &state.source {
+            VideoSource::Screen => create_input_context(state, &region).map(|ictx| ictx.input()),
+            VideoSource::TestPattern { width, height } => {
+                source::open_test_pattern(*width, *height, state.framerate)
+            }
+            VideoSource::File(path) => source::open_file(path),
+        };
+        let input = input.inspect_err(|e| {
+            error!(error = %e, "Failed to create input context");
         })?;
-        let mut input = ictx.input();
         let ist = input
             .streams()
             .best(ffmpeg::media::Type::Video)
-            .ok_or_else(|| anyhow::anyhow!("No video stream found"))?;
-        let ist_index = ist.index();
+            .ok_or_else(|| ServerError::Capture("No video stream found".to_string()))?;
+        let stream_index = ist.index();
+        let time_base = ist.time_base();
 
         // create decoder
         let mut decoder = ffmpeg::codec::context::Context::from_parameters(ist.parameters())
-            .map_err(|e| anyhow::anyhow!("Failed to create video decoder context: {}", e))?
+            .map_err(ServerError::ffmpeg(
+                "Failed to create video decoder context",
+            ))?
             .decoder()
             .video()
-            .map_err(|e| anyhow::anyhow!("Failed to create video decoder: {}", e))?;
+            .map_err(ServerError::ffmpeg("Failed to create video decoder"))?;
         decoder.set_threading(ffmpeg::threading::Config {
             kind: ffmpeg::threading::Type::Frame,
             count: 0,
         });
 
-        // create scaler
-        let mut scaler = ffmpeg::software::scaling::Context::get(
-            decoder.format(),
-            decoder.width(),
-            decoder.height(),
-            ffmpeg::format::Pixel::YUV420P,
-            decoder.width(),
-            decoder.height(),
-            ffmpeg::software::scaling::flag::Flags::FAST_BILINEAR,
-        )
-        .map_err(|e| anyhow::anyhow!("Failed to create video scaler: {}", e))?;
-
-        // set up encoder for WebRTC
-        let (encoder_codec, codec_name) = if hwaccel {
-            HW_ENCODERS
-                .iter()
-                .find_map(|name| {
-                    ffmpeg::codec::encoder::find_by_name(name).map(|encoder| {
-                        println!("Successfully found hardware encoder: {}", name);
-                        (encoder, *name)
-                    })
-                })
-                .unwrap_or_else(|| {
-                    println!(
-                        "No hardware encoders found. Falling back to software encoder (libx264)."
+        // crop to the region when the input device captured more than that
+        let crop = source_crop(&state.device, &region, decoder.width(), decoder.height())?;
+
+        info!(
+            source = %state.source,
+            monitor = %state.device,
+            region = %region,
+            backend = %CaptureBackend::Ffmpeg,
+            "Starting capture"
+        );
+
+        Ok(FfmpegCapture {
+            input,
+            stream_index,
+            time_base,
+            decoder,
+            crop,
+            decoded_frame: ffmpeg::frame::Video::empty(),
+            paced: !state.source.is_screen(),
+            looped: matches!(state.source, VideoSource::File(_)),
+            start: Instant::now(),
+            first_timestamp: None,
+            pts_offset: 0,
+            last_pts: 0,
+            frame_duration: (ENCODER_TIME_BASE.denominator() as u32 / state.framerate) as i64,
+        })
+    }
+
+    /// Start a looping input over, with timestamps carrying on from where it ended.
+    fn rewind(&mut self) -> Result<(), ServerError> {
+        debug!("Video file ended, starting over");
+        self.input
+            .seek(0, ..)
+            .map_err(ServerError::ffmpeg("Failed to rewind video file"))?;
+        self.decoder.flush();
+        self.first_timestamp = None;
+        self.pts_offset = self.last_pts + self.frame_duration;
+        Ok(())
+    }
+}
+
+impl CaptureSource for FfmpegCapture {
+    fn next_frame(&mut self) -> Result<Option<&mut ffmpeg::frame::Video>, ServerError> {
+        loop {
+            if self.decoder.receive_frame(&mut self.decoded_frame).is_ok() {
+                let pts = self.pts_offset
+                    + capture_pts(
+                        self.decoded_frame.timestamp(),
+                        self.time_base,
+                        &mut self.first_timestamp,
+                        self.start,
                     );
-                    (
-                        ffmpeg::codec::encoder::find(ffmpeg::codec::Id::H264)
-                            .expect("Default H264 software encoder (libx264) not found."),
-                        "libx264",
-                    )
-                })
-        } else {
-            (
-                ffmpeg::codec::encoder::find(ffmpeg::codec::Id::H264)
-                    .ok_or(anyhow::anyhow!("H264 encoder not found"))?,
-                "libx264",
-            )
-        };
+                self.last_pts = pts;
 
-        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
-            .encoder()
-            .video()
-            .map_err(|e| anyhow::anyhow!("Failed to create video encoder context: {}", e))?;
-
-        encoder_ctx.set_height(decoder.height());
-        encoder_ctx.set_width(decoder.width());
-        encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
-        encoder_ctx.set_color_range(ffmpeg::util::color::Range::MPEG);
-        encoder_ctx.set_colorspace(ffmpeg::util::color::Space::BT709);
-
-        let encoder_time_base = ffmpeg::Rational(1, 90000);
-        encoder_ctx.set_time_base(encoder_time_base);
-
-        let mut opts = ffmpeg::Dictionary::new();
-        match codec_name {
-            "h264_nvenc" => {
-                opts.set("preset", "p3");
-                opts.set("tune", "ull");
-                opts.set("zerolatency", "1");
-                opts.set("delay", "0");
-                opts.set("profile", "high");
-                opts.set("level", "5.2");
-                opts.set("g", "15");
-            }
-            "h264_amf" => {
-                opts.set("usage", "ultralowlatency");
-                opts.set("quality", "balanced");
-                opts.set("rc", "cqp");
-                opts.set("qp_i", "23");
-                opts.set("qp_p", "23");
-                opts.set("profile", "high");
-                opts.set("level", "5.2");
-                opts.set("g", "15");
-            }
-            "h264_qsv" => {
-                opts.set("preset", "fast");
-                opts.set("global_quality", "23");
-                opts.set("look_ahead", "0");
-                opts.set("profile", "high");
-                opts.set("level", "5.2");
-                opts.set("g", "15");
-            }
-            "h264_videotoolbox" => {
-                opts.set("allow_b_frames", "0");
-                opts.set("profile", "high");
-                opts.set("g", "15");
-            }
-            "h264_vaapi" => {
-                opts.set("rc_mode", "CQP");
-                opts.set("qp", "23");
-                opts.set("profile", "100");
-                opts.set("g", "15");
-            }
-            _ => {
-                // default to libx264 settings
-                opts.set("preset", "fast");
-                opts.set("tune", "zerolatency");
-                opts.set("crf", "21");
-                opts.set("sc_threshold", "0");
-                opts.set("profile", "high");
-                opts.set("level", "5.2");
-                opts.set("keyint", "15");
+                if self.paced {
+                    let due = self.start + pts_duration(pts);
+                    if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+
+                if let Some(crop) = &self.crop {
+                    crop.apply(&mut self.decoded_frame)?;
+                }
+                self.decoded_frame.set_pts(Some(pts));
+                return Ok(Some(&mut self.decoded_frame));
             }
-        };
 
-        let mut encoder = encoder_ctx
-            .open_with(opts)
-            .map_err(|e| anyhow::anyhow!("Failed to open encoder: {}", e))?;
-
-        println!("Starting capture on monitor: {}", state.device);
-
-        let mut decoded_frame = ffmpeg::frame::Video::empty();
-        let target_frame_duration = Duration::from_secs_f64(1.0 / state.framerate as f64);
-
-        for (stream, packet) in input.packets() {
-            if stream.index() == ist_index {
-                // decode packet
-                decoder.send_packet(&packet)?;
-                let mut scaled_frame = ffmpeg::frame::Video::empty();
-                while decoder.receive_frame(&mut decoded_frame).is_ok() {
-                    // scale to YUV format
-                    scaled_frame.set_pts(decoded_frame.pts());
-                    scaler.run(&decoded_frame, &mut scaled_frame)?;
-
-                    // encode to H264
-                    encoder.send_frame(&scaled_frame)?;
-                    let mut encoded_packet = ffmpeg::Packet::empty();
-                    while encoder.receive_packet(&mut encoded_packet).is_ok() {
-                        if state.video_track.try_lock().is_ok_and(|t| t.is_some()) {
-                            // send to WebRTC
-                            let packet_data = encoded_packet.data().unwrap().to_vec();
-
-                            let sample = Sample {
-                                data: packet_data.into(),
-                                duration: target_frame_duration,
-                                ..Default::default()
-                            };
-
-                            let _ = tx.try_send(sample);
-                        }
+            // decode packet
+            let mut packet = ffmpeg::Packet::empty();
+            match packet.read(&mut self.input) {
+                Ok(()) => {
+                    if packet.stream() == self.stream_index {
+                        self.decoder
+                            .send_packet(&packet)
+                            .map_err(ServerError::ffmpeg("Failed to decode captured frame"))?;
                     }
                 }
+                Err(ffmpeg::Error::Eof) if self.looped => self.rewind()?,
+                Err(ffmpeg::Error::Eof) => return Ok(None),
+                // devices may have nothing to read yet
+                Err(_) => {}
             }
+        }
+    }
+}
 
-            if shutdown_signal_clone.load(Ordering::Relaxed) {
-                break;
-            }
+/// pts in the encoder time base of a frame the input device stamped with `timestamp`,
+/// counted from the first frame's.
+///
+/// Frames without a timestamp are stamped with the time since `start` instead.
+fn capture_pts(
+    timestamp: Option<i64>,
+    time_base: ffmpeg::Rational,
+    first_timestamp: &mut Option<i64>,
+    start: Instant,
+) -> i64 {
+    match timestamp {
+        Some(timestamp) => {
+            let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+            (timestamp - first_timestamp).rescale(time_base, ENCODER_TIME_BASE)
         }
+        None => elapsed_pts(start),
+    }
+}
 
-        Ok(())
-    });
+/// Time since `start` in the encoder time base.
+pub(super) fn elapsed_pts(start: Instant) -> i64 {
+    (start.elapsed().as_micros() as i64).rescale(ffmpeg::Rational(1, 1_000_000), ENCODER_TIME_BASE)
+}
 
-    tokio::select! {
-        capture_result = capture_task => {
-            capture_result?
-        }
-        send_result = send_task => {
-            send_result?
-        }
-        _ = shutdown_rx.recv() => {
-            println!("Shutting down screen capture...");
-            shutdown_signal.store(true, Ordering::Relaxed);
-            Ok(())
-        }
+/// Time from the start of the capture to `pts`.
+fn pts_duration(pts: i64) -> Duration {
+    Duration::from_micros(
+        pts.max(0)
+            .rescale(ENCODER_TIME_BASE, ffmpeg::Rational(1, 1_000_000)) as u64,
+    )
+}
+
+/// Size to encode a `width`x`height` capture at, which YUV 4:2:0 needs to be even.
+pub(super) fn output_dimensions(state: &AppState, width: u32, height: u32) -> (u32, u32) {
+    match &state.output_size {
+        Some(output_size) => output_size.apply(width, height),
+        None => (width & !1, height & !1),
     }
 }
 
 pub async fn capture_mouse(
     state: Arc<AppState>,
     mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<()> {
+) -> Result<(), ServerError> {
+    // test patterns and files have no cursor to follow
+    if !state.source.is_screen() {
+        return Ok(());
+    }
+
     let (tx, mut rx) = mpsc::channel::<MousePosition>(16);
     let state_clone = state.clone();
 
@@ -288,23 +515,24 @@ pub async fn capture_mouse(
     let shutdown_signal_clone = shutdown_signal.clone();
 
     let send_task = tokio::spawn(async move {
-        while !shutdown_signal_clone.load(Ordering::Relaxed) {
-            if let Some(position) = rx.recv().await {
-                if let Some(mouse_channel) = state_clone.mouse_channel.lock().await.as_mut() {
-                    if mouse_channel.ready_state()
-                        == webrtc::data_channel::data_channel_state::RTCDataChannelState::Open
-                    {
-                        let msg = serde_json::to_string(&position).unwrap();
-                        if let Err(err) = mouse_channel.send_text(msg).await {
-                            eprintln!("Error sending mouse position: {}", err);
-                            continue;
-                        }
-                    }
+        // ends once the capture task has stopped and dropped the sender
+        while let Some(position) = rx.recv().await {
+            if shutdown_signal_clone.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let msg = serde_json::to_string(&position).unwrap();
+            for mouse_channel in state_clone.mouse_channels().await {
+                if mouse_channel.ready_state()
+                    == webrtc::data_channel::data_channel_state::RTCDataChannelState::Open
+                    && let Err(err) = mouse_channel.send_text(msg.clone()).await
+                {
+                    warn!(error = %err, "Error sending mouse position");
                 }
             }
         }
 
-        Ok(())
+        Ok::<(), ServerError>(())
     });
 
     let shutdown_signal_clone = shutdown_signal.clone();
@@ -312,34 +540,36 @@ pub async fn capture_mouse(
         // capture mouse position every at ~60 FPS
         let mut interval = tokio::time::interval(Duration::from_millis(16));
         let mut last_position = MousePosition { x: -1.0, y: -1.0 };
+        let mut read_pointer = pointer_reader(&state)?;
 
-        println!("Starting mouse capture...");
+        info!("Starting mouse capture...");
 
         while !shutdown_signal_clone.load(Ordering::Relaxed) {
             interval.tick().await;
 
-            match Mouse::get_mouse_position() {
-                Mouse::Position { x, y } => {
-                    let mut relative_x = if state.device.width > 0 {
-                        (x - state.device.x) as f64 / state.device.width as f64
+            match read_pointer() {
+                Some((x, y)) => {
+                    let region = state.region();
+                    let mut relative_x = if region.width > 0 {
+                        (x - region.x) as f64 / region.width as f64
                     } else {
                         0.0
                     };
 
-                    let mut relative_y = if state.device.height > 0 {
-                        (y - state.device.y) as f64 / state.device.height as f64
+                    let mut relative_y = if region.height > 0 {
+                        (y - region.y) as f64 / region.height as f64
                     } else {
                         0.0
                     };
 
                     // set to -1 if out of bounds
-                    relative_x = if relative_x >= 0.0 && relative_x <= 1.0 {
+                    relative_x = if (0.0..=1.0).contains(&relative_x) {
                         relative_x
                     } else {
                         -1.0
                     };
 
-                    relative_y = if relative_y >= 0.0 && relative_y <= 1.0 {
+                    relative_y = if (0.0..=1.0).contains(&relative_y) {
                         relative_y
                     } else {
                         -1.0
@@ -358,41 +588,140 @@ pub async fn capture_mouse(
                         let _ = tx.try_send(current_position);
                     }
                 }
-                Mouse::Error => {
-                    eprintln!("Failed to capture mouse position");
+                None => {
+                    error!("Failed to capture mouse position");
                     break;
                 }
             };
         }
 
-        Ok(())
+        Ok::<(), ServerError>(())
     });
 
     tokio::select! {
         capture_result = capture_task => {
-            capture_result?
+            capture_result?.inspect_err(|e| error!(error = %e, "Mouse capture failed"))
         }
         send_result = send_task => {
             send_result?
         }
         _ = shutdown_rx.recv() => {
-            println!("Shutting down screen capture...");
+            info!("Shutting down mouse capture...");
             shutdown_signal.store(true, Ordering::Relaxed);
             Ok(())
         }
     }
 }
 
+/// Reads where the pointer is in desktop coordinates, `None` once it cannot be read.
+#[cfg(target_os = "linux")]
+fn pointer_reader(
+    state: &AppState,
+) -> Result<impl FnMut() -> Option<(i32, i32)> + Send, ServerError> {
+    let display = state
+        .x11_display
+        .as_deref()
+        .ok_or(ServerError::NoX11Display)?;
+    let pointer = X11Pointer::open(display)?;
+    Ok(move || pointer.position())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pointer_reader(
+    _state: &AppState,
+) -> Result<impl FnMut() -> Option<(i32, i32)> + Send, ServerError> {
+    Ok(|| match Mouse::get_mouse_position() {
+        Mouse::Position { x, y } => Some((x, y)),
+        Mouse::Error => None,
+    })
+}
+
+/// Part of a decoded frame to keep, in pixels of the frame.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+struct Crop {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Crop {
+    fn apply(&self, frame: &mut ffmpeg::frame::Video) -> Result<(), ServerError> {
+        unsafe {
+            let ptr = frame.as_mut_ptr();
+            (*ptr).crop_left = self.left as usize;
+            (*ptr).crop_top = self.top as usize;
+            (*ptr).crop_right = ((*ptr).width as u32 - self.left - self.width) as usize;
+            (*ptr).crop_bottom = ((*ptr).height as u32 - self.top - self.height) as usize;
+            let ret = ffmpeg::ffi::av_frame_apply_cropping(ptr, 0);
+            if ret < 0 {
+                return Err(ServerError::Ffmpeg {
+                    context: "Failed to crop captured frame",
+                    source: ffmpeg::Error::from(ret),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// gdigrab and x11grab capture the region directly, so nothing is left to crop.
+#[cfg(not(target_os = "macos"))]
+fn source_crop(
+    _device: &CaptureDevice,
+    _region: &Region,
+    _width: u32,
+    _height: u32,
+) -> Result<Option<Crop>, ServerError> {
+    Ok(None)
+}
+
+/// avfoundation always captures the whole screen, so cut the region out of it.
+///
+/// The frame may be larger than the monitor's logical size on Retina displays,
+/// so the region is scaled to the frame's pixels.
+#[cfg(target_os = "macos")]
+fn source_crop(
+    device: &CaptureDevice,
+    region: &Region,
+    width: u32,
+    height: u32,
+) -> Result<Option<Crop>, ServerError> {
+    if *region == device.bounds() {
+        return Ok(None);
+    }
+    if !device.bounds().contains(region) {
+        return Err(ServerError::RegionOutOfBounds {
+            region: *region,
+            bounds: device.bounds(),
+        });
+    }
+
+    let scale_x = width as f64 / device.width as f64;
+    let scale_y = height as f64 / device.height as f64;
+    let left = ((region.x - device.x) as f64 * scale_x) as u32;
+    let top = ((region.y - device.y) as f64 * scale_y) as u32;
+
+    Ok(Some(Crop {
+        left,
+        top,
+        width: ((region.width as f64 * scale_x) as u32).min(width - left),
+        height: ((region.height as f64 * scale_y) as u32).min(height - top),
+    }))
+}
+
 #[cfg(target_os = "windows")]
 fn create_input_context(
-    capture: &CaptureDevice,
-    framerate: u32,
-) -> Result<ffmpeg::format::context::Context> {
+    state: &AppState,
+    capture: &Region,
+) -> Result<ffmpeg::format::context::Context, ServerError> {
     // find capture device
     let input_device = ffmpeg::device::input::video()
         .into_iter()
         .find(|d| d.name() == "gdigrab")
-        .ok_or(anyhow::anyhow!("gdigrab input device not found"))?;
+        .ok_or(ServerError::Capture(
+            "gdigrab input device not found".to_string(),
+        ))?;
 
     // set input options
     let mut input_options = ffmpeg::Dictionary::new();
@@ -402,26 +731,29 @@ fn create_input_context(
         "video_size",
         &format!("{}x{}", capture.width, capture.height),
     );
-    input_options.set("framerate", &framerate.to_string());
+    input_options.set("framerate", &state.framerate.to_string());
     input_options.set("draw_mouse", "0");
 
     // set device path
     let video_path = "desktop".to_string();
 
-    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)?;
+    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)
+        .map_err(ServerError::ffmpeg("Failed to open capture device"))?;
     Ok(ictx)
 }
 
 #[cfg(target_os = "linux")]
 fn create_input_context(
-    capture: &CaptureDevice,
-    framerate: u32,
-) -> Result<ffmpeg::format::context::Context> {
+    state: &AppState,
+    capture: &Region,
+) -> Result<ffmpeg::format::context::Context, ServerError> {
     // find capture device
     let input_device = ffmpeg::device::input::video()
         .into_iter()
         .find(|d| d.name() == "x11grab")
-        .ok_or(anyhow::anyhow!("x11grab input device not found"))?;
+        .ok_or(ServerError::Capture(
+            "x11grab input device not found".to_string(),
+        ))?;
 
     // set input options
     let mut input_options = ffmpeg::Dictionary::new();
@@ -429,37 +761,45 @@ fn create_input_context(
         "video_size",
         &format!("{}x{}", capture.width, capture.height),
     );
-    input_options.set("framerate", &framerate.to_string());
+    input_options.set("framerate", &state.framerate.to_string());
     input_options.set("draw_mouse", "0");
 
-    // set device path
-    let video_path = format!(":0.0+{},{}", capture.x, capture.y);
+    // set device path, on the display that was checked when the server was built
+    let display = state
+        .x11_display
+        .as_deref()
+        .ok_or(ServerError::NoX11Display)?;
+    let video_path = format!("{}+{},{}", display, capture.x, capture.y);
 
-    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)?;
+    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)
+        .map_err(ServerError::ffmpeg("Failed to open capture device"))?;
     Ok(ictx)
 }
 
 #[cfg(target_os = "macos")]
 fn create_input_context(
-    capture: &CaptureDevice,
-    framerate: u32,
-) -> Result<ffmpeg::format::context::Context> {
+    state: &AppState,
+    _region: &Region,
+) -> Result<ffmpeg::format::context::Context, ServerError> {
     // find capture device
     let input_device = ffmpeg::device::input::video()
         .into_iter()
         .find(|d| d.name() == "avfoundation")
-        .ok_or(anyhow::anyhow!("avfoundation input device not found"))?;
+        .ok_or(ServerError::Capture(
+            "avfoundation input device not found".to_string(),
+        ))?;
 
     // set input options
     let mut input_options = ffmpeg::Dictionary::new();
-    input_options.set("framerate", &framerate.to_string());
+    input_options.set("framerate", &state.framerate.to_string());
     input_options.set("pixel_format", "uyvy422");
     input_options.set("capture_cursor", "0");
     input_options.set("capture_mouse_clicks", "0");
 
     // set device path
-    let video_path = format!("{}:", capture.index + 1);
+    let video_path = format!("{}:", state.device.index + 1);
 
-    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)?;
+    let ictx = ffmpeg::format::open_with(&video_path, &input_device, input_options)
+        .map_err(ServerError::ffmpeg("Failed to open capture device"))?;
     Ok(ictx)
 }
@@ -0,0 +1,66 @@
+use ffmpeg_next as ffmpeg;
+
+/// Tells whether a captured frame differs from the one before it, so a static screen is
+/// not encoded again and again.
+///
+/// The previous frame is kept as a copy of its rows. Comparing rows is a `memcmp`, much
+/// cheaper than scaling and encoding, and only the rows from the first difference on are
+/// copied when the screen changes.
+pub(super) struct ChangeDetector {
+    previous: Vec<u8>,
+}
+
+impl ChangeDetector {
+    pub fn new() -> Self {
+        ChangeDetector { previous: vec![] }
+    }
+
+    /// Whether the frame made of `rows` differs from the last one, which it replaces.
+    pub fn changed<'a>(&mut self, rows: impl Iterator<Item = &'a [u8]>) -> bool {
+        let mut changed = false;
+        let mut offset = 0;
+        for row in rows {
+            let end = offset + row.len();
+            if !changed {
+                if self.previous.get(offset..end) == Some(row) {
+                    offset = end;
+                    continue;
+                }
+                changed = true;
+                self.previous.truncate(offset);
+            }
+            self.previous.extend_from_slice(row);
+            offset = end;
+        }
+
+        // a frame that got smaller still matches the start of the previous one
+        if offset != self.previous.len() {
+            changed = true;
+            self.previous.truncate(offset);
+        }
+        changed
+    }
+}
+
+/// The pixels of every row of every plane of `frame`, without the padding at the end of
+/// the rows.
+pub(super) fn frame_rows(frame: &ffmpeg::frame::Video) -> impl Iterator<Item = &[u8]> {
+    let mut row_sizes = [0; 4];
+    unsafe {
+        ffmpeg::ffi::av_image_fill_linesizes(
+            row_sizes.as_mut_ptr(),
+            frame.format().into(),
+            frame.width() as i32,
+        );
+    }
+
+    (0..frame.planes().min(row_sizes.len())).flat_map(move |plane| {
+        let row_size = row_sizes[plane].max(0) as usize;
+        let stride = frame.stride(plane);
+        frame
+            .data(plane)
+            .chunks(stride)
+            .take(frame.plane_height(plane) as usize)
+            .map(move |row| &row[..row_size.min(row.len())])
+    })
+}
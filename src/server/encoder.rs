@@ -0,0 +1,873 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next as ffmpeg;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use webrtc::media::Sample;
+
+use super::{
+    AppState, EncoderPreset,
+    h264::{self, H264Profile},
+};
+use crate::{
+    error::ServerError,
+    shared::{ProbeResult, VideoCodec, init_ffmpeg, test_encode},
+};
+
+#[cfg(target_os = "windows")]
+const H264_HW_ENCODERS: &[&str] = &[
+    "h264_nvenc", // NVIDIA NVENC
+    "h264_amf",   // AMD AMF
+    "h264_qsv",   // Intel Quick Sync Video
+    "h264_mf",    // Microsoft Media Foundation
+];
+
+#[cfg(target_os = "windows")]
+const H265_HW_ENCODERS: &[&str] = &[
+    "hevc_nvenc", // NVIDIA NVENC
+    "hevc_amf",   // AMD AMF
+    "hevc_qsv",   // Intel Quick Sync Video
+    "hevc_mf",    // Microsoft Media Foundation
+];
+
+#[cfg(target_os = "windows")]
+const VP8_HW_ENCODERS: &[&str] = &[];
+
+#[cfg(target_os = "windows")]
+const VP9_HW_ENCODERS: &[&str] = &[
+    "vp9_qsv", // Intel Quick Sync Video
+];
+
+#[cfg(target_os = "windows")]
+const AV1_HW_ENCODERS: &[&str] = &[
+    "av1_nvenc", // NVIDIA NVENC
+    "av1_amf",   // AMD AMF
+    "av1_qsv",   // Intel Quick Sync Video
+];
+
+#[cfg(target_os = "macos")]
+const H264_HW_ENCODERS: &[&str] = &[
+    "h264_videotoolbox", // Apple VideoToolbox
+];
+
+#[cfg(target_os = "macos")]
+const H265_HW_ENCODERS: &[&str] = &[
+    "hevc_videotoolbox", // Apple VideoToolbox
+];
+
+#[cfg(target_os = "macos")]
+const VP8_HW_ENCODERS: &[&str] = &[];
+
+#[cfg(target_os = "macos")]
+const VP9_HW_ENCODERS: &[&str] = &[];
+
+#[cfg(target_os = "macos")]
+const AV1_HW_ENCODERS: &[&str] = &[];
+
+#[cfg(target_os = "linux")]
+const H264_HW_ENCODERS: &[&str] = &[
+    "h264_nvenc", // NVIDIA NVENC
+    "h264_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const H265_HW_ENCODERS: &[&str] = &[
+    "hevc_nvenc", // NVIDIA NVENC
+    "hevc_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const VP8_HW_ENCODERS: &[&str] = &[
+    "vp8_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const VP9_HW_ENCODERS: &[&str] = &[
+    "vp9_vaapi", // Intel/AMD VA-API
+];
+
+#[cfg(target_os = "linux")]
+const AV1_HW_ENCODERS: &[&str] = &[
+    "av1_nvenc", // NVIDIA NVENC
+    "av1_vaapi", // Intel/AMD VA-API
+];
+
+/// Hardware encoders for a codec, in order of preference.
+fn hw_encoders(codec: VideoCodec) -> &'static [&'static str] {
+    match codec {
+        VideoCodec::H264 => H264_HW_ENCODERS,
+        VideoCodec::H265 => H265_HW_ENCODERS,
+        VideoCodec::Vp8 => VP8_HW_ENCODERS,
+        VideoCodec::Vp9 => VP9_HW_ENCODERS,
+        VideoCodec::Av1 => AV1_HW_ENCODERS,
+    }
+}
+
+/// FFmpeg's software encoders for a codec, in order of preference.
+fn sw_encoders(codec: VideoCodec) -> &'static [&'static str] {
+    match codec {
+        VideoCodec::H264 => &["libx264"],
+        VideoCodec::H265 => &["libx265"],
+        VideoCodec::Vp8 => &["libvpx"],
+        VideoCodec::Vp9 => &["libvpx-vp9"],
+        VideoCodec::Av1 => &["libsvtav1", "libaom-av1", "librav1e"],
+    }
+}
+
+/// Encoders that pick up a new bitrate on the next frame, others are reopened.
+const LIVE_BITRATE_ENCODERS: &[&str] = &["libx264", "h264_nvenc", "hevc_nvenc", "av1_nvenc"];
+
+/// Minimum time between two bitrate changes.
+const BITRATE_CHANGE_INTERVAL: Duration = Duration::from_secs(1);
+/// Relative change of the target bitrate needed before the encoder is touched.
+const BITRATE_CHANGE_THRESHOLD: f64 = 0.1;
+
+/// Seconds between periodic keyframes.
+const KEYFRAME_INTERVAL_SECS: u32 = 10;
+/// Minimum time between two keyframes forced by viewer requests.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Default time between frames encoded while the screen does not change, which keeps
+/// the viewers' bandwidth estimates and loss reports going.
+pub(super) const DEFAULT_IDLE_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Time base of the encoders, the 90 kHz clock of RTP video so pts are RTP time.
+pub(super) const ENCODER_TIME_BASE: ffmpeg::Rational = ffmpeg::Rational(1, 90000);
+
+/// Bits per pixel per frame below which the frame rate is halved, trading smoothness
+/// for sharper frames on a slow link.
+const LOW_BITS_PER_PIXEL: f64 = 0.02;
+
+/// What one encoder produces: a codec, and for H.264 the profile its viewers decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct StreamFormat {
+    pub codec: VideoCodec,
+    pub h264_profile: Option<H264Profile>,
+}
+
+impl Display for StreamFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.h264_profile {
+            Some(profile) => write!(f, "{} ({})", self.codec, profile),
+            None => write!(f, "{}", self.codec),
+        }
+    }
+}
+
+/// An encoded frame, for the viewers streaming its format.
+pub(super) struct EncodedFrame {
+    pub format: StreamFormat,
+    /// RTP time the frame was captured at, counted from the start of the capture.
+    pub timestamp: u32,
+    pub sample: Sample,
+}
+
+/// Encoders for every format the viewers use, all fed the same captured frames.
+///
+/// An encoder is opened when the first viewer with its format arrives and closed when
+/// the last one leaves, so nothing is encoded while nobody watches.
+pub(super) struct EncoderSet {
+    width: u32,
+    height: u32,
+    encoders: Vec<VideoEncoder>,
+    /// Which encoders drop the current frame to lower their frame rate.
+    skipped: Vec<bool>,
+}
+
+impl EncoderSet {
+    pub fn new(width: u32, height: u32) -> Self {
+        EncoderSet {
+            width,
+            height,
+            encoders: vec![],
+            skipped: vec![],
+        }
+    }
+
+    /// Whether the next captured frame can be dropped because no encoder needs it.
+    ///
+    /// `changed` tells whether the frame differs from the previous one. It is only called
+    /// when someone is watching and unchanged frames are not encoded.
+    pub fn skip_frame(
+        &mut self,
+        state: &AppState,
+        changed: impl FnOnce() -> bool,
+    ) -> Result<bool, ServerError> {
+        self.sync(state)?;
+        if self.encoders.is_empty() {
+            return Ok(true);
+        }
+
+        if state.take_keyframe_request() {
+            for encoder in &mut self.encoders {
+                encoder.keyframe_requested = true;
+            }
+        }
+
+        let changed = state.idle_heartbeat.is_none() || changed();
+        self.skipped = self
+            .encoders
+            .iter_mut()
+            .map(|encoder| encoder.skip_frame(changed, state.idle_heartbeat))
+            .collect();
+        Ok(self.skipped.iter().all(|&skipped| skipped))
+    }
+
+    /// Whether nobody watches, so there is no need to capture at all.
+    pub fn is_idle(&mut self, state: &AppState) -> Result<bool, ServerError> {
+        self.sync(state)?;
+        Ok(self.encoders.is_empty())
+    }
+
+    /// Encode a frame with every encoder that did not skip it.
+    pub fn encode(
+        &mut self,
+        frame: &mut ffmpeg::frame::Video,
+        state: &AppState,
+        tx: &mpsc::Sender<EncodedFrame>,
+    ) -> Result<(), ServerError> {
+        for (encoder, &skipped) in self.encoders.iter_mut().zip(&self.skipped) {
+            if !skipped {
+                encoder.encode(frame, state, tx)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open and close encoders to match the formats of the current viewers.
+    fn sync(&mut self, state: &AppState) -> Result<(), ServerError> {
+        let Some(formats) = state.session_formats() else {
+            return Ok(());
+        };
+
+        self.encoders
+            .retain(|encoder| formats.contains(&encoder.format));
+        for format in formats {
+            if self.encoders.iter().any(|encoder| encoder.format == format) {
+                continue;
+            }
+            let encoder = VideoEncoder::open(state, format, self.width, self.height)?;
+            info!(
+                %format,
+                encoder = encoder.codec_name(),
+                width = self.width,
+                height = self.height,
+                "Started encoding"
+            );
+            self.encoders.push(encoder);
+        }
+        Ok(())
+    }
+}
+
+/// Video encoder for one format, following the bitrate the viewers can take.
+struct VideoEncoder {
+    encoder: ffmpeg::encoder::Video,
+    format: StreamFormat,
+    codec_name: String,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    bitrate: u64,
+    last_bitrate_change: Instant,
+    last_forced_keyframe: Option<Instant>,
+    keyframe_requested: bool,
+    frame_divisor: u32,
+    frame_count: u64,
+    /// When the last frame was encoded, `None` before the first one.
+    last_encoded: Option<Instant>,
+    /// Whether the screen changed since the last encoded frame.
+    unsent_change: bool,
+    /// pts of the last packet sent, which the next one has to be later than.
+    last_pts: Option<i64>,
+}
+
+impl VideoEncoder {
+    fn open(
+        state: &AppState,
+        format: StreamFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, ServerError> {
+        let framerate = state.framerate;
+        let bitrate = state.target_bitrate();
+        let (encoder, codec_name) = open_encoder(state, format, width, height, bitrate)?;
+
+        Ok(VideoEncoder {
+            encoder,
+            format,
+            codec_name,
+            width,
+            height,
+            framerate,
+            bitrate,
+            last_bitrate_change: Instant::now(),
+            last_forced_keyframe: None,
+            keyframe_requested: false,
+            frame_divisor: frame_divisor(bitrate, width, height, framerate),
+            frame_count: 0,
+            last_encoded: None,
+            unsent_change: false,
+            last_pts: None,
+        })
+    }
+
+    fn codec_name(&self) -> &str {
+        &self.codec_name
+    }
+
+    /// Whether the next captured frame should be dropped, either to lower the frame rate
+    /// or because the screen has not `changed`.
+    ///
+    /// A static screen is still encoded every `idle_heartbeat`, and right away when a
+    /// viewer asks for a keyframe.
+    fn skip_frame(&mut self, changed: bool, idle_heartbeat: Option<Duration>) -> bool {
+        self.unsent_change |= changed;
+        let heartbeat_due = match (idle_heartbeat, self.last_encoded) {
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+            _ => true,
+        };
+        if !self.unsent_change && !self.keyframe_requested && !heartbeat_due {
+            return true;
+        }
+
+        self.frame_count += 1;
+        self.frame_count % self.frame_divisor as u64 != 0
+    }
+
+    /// Encode a frame and queue its packets for the viewers.
+    ///
+    /// The frame's pts is its capture time in [`ENCODER_TIME_BASE`].
+    fn encode(
+        &mut self,
+        frame: &mut ffmpeg::frame::Video,
+        state: &AppState,
+        tx: &mpsc::Sender<EncodedFrame>,
+    ) -> Result<(), ServerError> {
+        self.adapt_bitrate(state, tx)?;
+
+        // turn a viewer's keyframe request into an IDR frame, at a bounded rate
+        let force_keyframe = self.keyframe_requested
+            && self
+                .last_forced_keyframe
+                .is_none_or(|last| last.elapsed() >= KEYFRAME_REQUEST_INTERVAL);
+        if force_keyframe {
+            debug!(format = %self.format, "Forcing keyframe");
+            self.keyframe_requested = false;
+            self.last_forced_keyframe = Some(Instant::now());
+            frame.set_kind(ffmpeg::picture::Type::I);
+        } else {
+            frame.set_kind(ffmpeg::picture::Type::None);
+        }
+
+        self.encoder
+            .send_frame(frame)
+            .map_err(ServerError::ffmpeg("Failed to encode captured frame"))?;
+        self.unsent_change = false;
+        self.last_encoded = Some(Instant::now());
+        self.send_packets(tx);
+
+        Ok(())
+    }
+
+    /// Queue the packets the encoder has ready, stamped with the pts of their frames.
+    ///
+    /// Encoders with a delay return the packets of earlier frames, and frames dropped by
+    /// rate control leave a gap, so the time between two packets is taken from their pts
+    /// rather than from the frame rate.
+    fn send_packets(&mut self, tx: &mpsc::Sender<EncodedFrame>) {
+        let frame_duration =
+            (ENCODER_TIME_BASE.denominator() as u32 * self.frame_divisor / self.framerate) as i64;
+
+        let mut encoded_packet = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut encoded_packet).is_ok() {
+            // send to WebRTC
+            let Some(packet_data) = encoded_packet.data() else {
+                continue;
+            };
+
+            // keep the timestamps increasing even when an encoder leaves out the pts
+            let pts = match (encoded_packet.pts(), self.last_pts) {
+                (Some(pts), Some(last_pts)) => pts.max(last_pts + 1),
+                (Some(pts), None) => pts,
+                (None, Some(last_pts)) => last_pts + frame_duration,
+                (None, None) => 0,
+            };
+            self.last_pts = Some(pts);
+
+            let sample = Sample {
+                data: packet_data.to_vec().into(),
+                ..Default::default()
+            };
+
+            let _ = tx.try_send(EncodedFrame {
+                format: self.format,
+                timestamp: pts as u32,
+                sample,
+            });
+        }
+    }
+
+    /// Move the encoder to the viewers' target bitrate, once it has moved far enough.
+    fn adapt_bitrate(
+        &mut self,
+        state: &AppState,
+        tx: &mpsc::Sender<EncodedFrame>,
+    ) -> Result<(), ServerError> {
+        let target = state.target_bitrate();
+        let change = (target as f64 - self.bitrate as f64).abs() / self.bitrate as f64;
+        if change < BITRATE_CHANGE_THRESHOLD
+            || self.last_bitrate_change.elapsed() < BITRATE_CHANGE_INTERVAL
+        {
+            return Ok(());
+        }
+
+        let frame_divisor = frame_divisor(target, self.width, self.height, self.framerate);
+        info!(
+            bitrate = target,
+            framerate = self.framerate / frame_divisor,
+            "Adapting encoder to available bandwidth"
+        );
+
+        if LIVE_BITRATE_ENCODERS.contains(&self.codec_name.as_str()) {
+            unsafe {
+                set_bitrate(self.encoder.as_mut_ptr(), target);
+            }
+        } else {
+            let (encoder, codec_name) =
+                open_encoder(state, self.format, self.width, self.height, target)?;
+            // send the frames the old encoder still holds before replacing it
+            if self.encoder.send_eof().is_ok() {
+                self.send_packets(tx);
+            }
+            self.encoder = encoder;
+            self.codec_name = codec_name;
+        }
+
+        self.bitrate = target;
+        self.frame_divisor = frame_divisor;
+        self.last_bitrate_change = Instant::now();
+        Ok(())
+    }
+}
+
+/// How many captured frames make one encoded frame at this bitrate.
+fn frame_divisor(bitrate: u64, width: u32, height: u32, framerate: u32) -> u32 {
+    let bits_per_pixel = bitrate as f64 / (width as f64 * height as f64 * framerate as f64);
+    if bits_per_pixel < LOW_BITS_PER_PIXEL && framerate >= 20 {
+        2
+    } else {
+        1
+    }
+}
+
+/// Set the average and peak bitrate, with half a second of buffer.
+unsafe fn set_bitrate(ctx: *mut ffmpeg::ffi::AVCodecContext, bitrate: u64) {
+    unsafe {
+        (*ctx).bit_rate = bitrate as i64;
+        (*ctx).rc_max_rate = bitrate as i64;
+        (*ctx).rc_buffer_size = (bitrate / 2) as i32;
+    }
+}
+
+/// Encoders for `codec` in this FFmpeg build, hardware ones first when `hwaccel` is set.
+fn encoder_candidates(codec: VideoCodec, hwaccel: bool) -> Vec<&'static str> {
+    let hw_encoder_names = if hwaccel { hw_encoders(codec) } else { &[] };
+    hw_encoder_names
+        .iter()
+        .chain(sw_encoders(codec))
+        .copied()
+        .filter(|name| ffmpeg::codec::encoder::find_by_name(name).is_some())
+        .collect()
+}
+
+/// Open an encoder and encode a small frame with it, which fails for hardware encoders
+/// that are built in but have no device to run on.
+fn try_encoder(name: &str) -> Result<(), String> {
+    let encoder = ffmpeg::codec::encoder::find_by_name(name)
+        .ok_or_else(|| "not in this FFmpeg build".to_string())?;
+    test_encode(encoder, ffmpeg::Dictionary::new()).map(|_| ())
+}
+
+/// Try every hardware and software encoder of every codec, for `wireless-display probe`.
+pub fn probe_encoders() -> Result<Vec<ProbeResult>, ServerError> {
+    init_ffmpeg().map_err(ServerError::ffmpeg("Failed to initialize FFmpeg"))?;
+
+    let mut results = vec![];
+    for &codec in VideoCodec::ALL {
+        for &name in hw_encoders(codec) {
+            results.push(ProbeResult::new(codec, name, true, try_encoder(name)));
+        }
+        for &name in sw_encoders(codec) {
+            results.push(ProbeResult::new(codec, name, false, try_encoder(name)));
+        }
+    }
+    Ok(results)
+}
+
+/// The codecs out of `codecs` that can be encoded here, each with its encoders in order
+/// of preference.
+///
+/// The first encoder of a codec is the first one that works, the ones after it are
+/// fallbacks for when it fails to open at the stream's size. A `forced` encoder is the
+/// only one for the codec it produces, which is moved to the front.
+pub(super) fn available_encoders(
+    codecs: &[VideoCodec],
+    hwaccel: bool,
+    forced: Option<&str>,
+) -> Result<Vec<(VideoCodec, Vec<String>)>, ServerError> {
+    init_ffmpeg().map_err(ServerError::ffmpeg("Failed to initialize FFmpeg"))?;
+
+    let forced = forced.map(forced_encoder).transpose()?;
+
+    let mut encoders = vec![];
+    for &codec in codecs {
+        if forced
+            .as_ref()
+            .is_some_and(|(forced_codec, _)| *forced_codec == codec)
+        {
+            continue;
+        }
+
+        let candidates = encoder_candidates(codec, hwaccel);
+        let working = candidates.iter().position(|name| match try_encoder(name) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(%codec, encoder = name, error = err, "Encoder does not work, trying the next one");
+                false
+            }
+        });
+        let Some(working) = working else {
+            warn!(%codec, "Codec is not available, no encoder works");
+            continue;
+        };
+
+        let name = candidates[working];
+        if hwaccel && !hw_encoders(codec).contains(&name) {
+            warn!(
+                %codec,
+                encoder = name,
+                "No hardware encoder works, falling back to a software encoder"
+            );
+        }
+        let names = candidates[working..]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        encoders.push((codec, names));
+    }
+    if let Some((codec, name)) = forced {
+        encoders.insert(0, (codec, vec![name]));
+    }
+
+    if encoders.is_empty() {
+        let codecs = codecs
+            .iter()
+            .map(VideoCodec::to_string)
+            .collect::<Vec<String>>();
+        return Err(ServerError::EncoderNotFound(codecs.join(", ")));
+    }
+
+    Ok(encoders)
+}
+
+/// Look up an encoder given by name and check that it works, with the codec it produces.
+fn forced_encoder(name: &str) -> Result<(VideoCodec, String), ServerError> {
+    let encoder = ffmpeg::codec::encoder::find_by_name(name)
+        .ok_or_else(|| ServerError::EncoderNotFound(name.to_string()))?;
+    let codec = VideoCodec::ALL
+        .iter()
+        .copied()
+        .find(|codec| codec.ffmpeg_id() == encoder.id())
+        .ok_or_else(|| ServerError::UnsupportedEncoder(name.to_string()))?;
+    try_encoder(name).map_err(|reason| ServerError::EncoderUnusable {
+        encoder: name.to_string(),
+        reason,
+    })?;
+    Ok((codec, name.to_string()))
+}
+
+/// Open the first encoder for the codec of `format` that opens, so a hardware encoder
+/// that fails at this size falls back to the next one.
+fn open_encoder(
+    state: &AppState,
+    format: StreamFormat,
+    width: u32,
+    height: u32,
+    bitrate: u64,
+) -> Result<(ffmpeg::encoder::Video, String), ServerError> {
+    let mut last_error = ServerError::EncoderNotFound(format.codec.to_string());
+    for name in state.encoder_names(format.codec) {
+        match open_named_encoder(state, name, format, width, height, bitrate) {
+            Ok(encoder) => return Ok((encoder, name.clone())),
+            Err(err) => {
+                warn!(encoder = name.as_str(), error = %err, "Failed to open encoder, trying the next one");
+                last_error = err;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Open the encoder called `codec_name` for `format`, tuned with the configured preset
+/// and options.
+///
+/// H.264 streams are tagged with the lowest level that covers the size, frame rate and
+/// maximum bitrate, which is also the level announced to the viewers.
+fn open_named_encoder(
+    state: &AppState,
+    codec_name: &str,
+    format: StreamFormat,
+    width: u32,
+    height: u32,
+    bitrate: u64,
+) -> Result<ffmpeg::encoder::Video, ServerError> {
+    // set up encoder for WebRTC
+    let codec = format.codec;
+    let framerate = state.framerate;
+    let encoder_codec = ffmpeg::codec::encoder::find_by_name(codec_name)
+        .ok_or_else(|| ServerError::EncoderNotFound(codec_name.to_string()))?;
+
+    let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+        .encoder()
+        .video()
+        .map_err(ServerError::ffmpeg(
+            "Failed to create video encoder context",
+        ))?;
+
+    encoder_ctx.set_height(height);
+    encoder_ctx.set_width(width);
+    encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
+    encoder_ctx.set_color_range(ffmpeg::util::color::Range::MPEG);
+    encoder_ctx.set_colorspace(ffmpeg::util::color::Space::BT709);
+    encoder_ctx.set_frame_rate(Some(ffmpeg::Rational(framerate as i32, 1)));
+    unsafe {
+        set_bitrate(encoder_ctx.as_mut_ptr(), bitrate);
+    }
+
+    encoder_ctx.set_time_base(ENCODER_TIME_BASE);
+
+    // the profile and level viewers were promised, also set on the context for the
+    // encoders without options of their own
+    let profile = format.h264_profile.unwrap_or(H264Profile::High);
+    let level_idc = h264::level_idc(profile, width, height, framerate, state.max_bitrate);
+    let level = h264::level_name(level_idc);
+    if codec == VideoCodec::H264 {
+        unsafe {
+            let ctx = encoder_ctx.as_mut_ptr();
+            (*ctx).profile = profile.ffmpeg_profile();
+            (*ctx).level = level_idc as i32;
+        }
+    }
+
+    // keyframes come on request when a viewer loses data, so the periodic ones can be rare
+    let gop = (framerate * KEYFRAME_INTERVAL_SECS).to_string();
+
+    // rate control is capped by the bitrate set above, so it can follow the network
+    let preset = state.preset;
+    let mut opts = ffmpeg::Dictionary::new();
+    match codec_name {
+        "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => {
+            opts.set("preset", preset.pick("p1", "p3", "p5", "p6"));
+            opts.set("tune", preset.pick("ull", "ull", "ll", "ll"));
+            opts.set("zerolatency", "1");
+            opts.set("delay", "0");
+            opts.set("rc", "vbr");
+            opts.set("forced-idr", "1");
+            if preset == EncoderPreset::LowBandwidth {
+                opts.set("spatial-aq", "1");
+            }
+            match codec {
+                VideoCodec::H264 => {
+                    opts.set("profile", profile.option_name());
+                    opts.set("level", &level);
+                }
+                VideoCodec::H265 => opts.set("profile", "main"),
+                _ => {}
+            }
+            opts.set("g", &gop);
+        }
+        "h264_amf" | "hevc_amf" | "av1_amf" => {
+            opts.set(
+                "usage",
+                preset.pick(
+                    "ultralowlatency",
+                    "ultralowlatency",
+                    "lowlatency",
+                    "lowlatency",
+                ),
+            );
+            opts.set(
+                "quality",
+                preset.pick("speed", "balanced", "quality", "quality"),
+            );
+            opts.set("rc", "vbr_peak");
+            match codec {
+                VideoCodec::H264 => {
+                    opts.set(
+                        "profile",
+                        match profile {
+                            H264Profile::ConstrainedBaseline => "constrained_baseline",
+                            _ => profile.option_name(),
+                        },
+                    );
+                    opts.set("level", &level);
+                }
+                VideoCodec::H265 => opts.set("profile", "main"),
+                _ => {}
+            }
+            opts.set("g", &gop);
+        }
+        "h264_qsv" | "hevc_qsv" | "vp9_qsv" | "av1_qsv" => {
+            opts.set("preset", preset.pick("veryfast", "fast", "medium", "slow"));
+            match codec {
+                VideoCodec::H264 => {
+                    opts.set("look_ahead", "0");
+                    opts.set("profile", profile.option_name());
+                }
+                VideoCodec::H265 => {
+                    opts.set("look_ahead", "0");
+                    opts.set("profile", "main");
+                }
+                VideoCodec::Av1 => opts.set("look_ahead_depth", "0"),
+                _ => {}
+            }
+            opts.set("g", &gop);
+        }
+        "h264_videotoolbox" | "hevc_videotoolbox" => {
+            opts.set("allow_b_frames", "0");
+            match preset {
+                EncoderPreset::Latency => {
+                    opts.set("realtime", "1");
+                    opts.set("prio_speed", "1");
+                }
+                EncoderPreset::Quality | EncoderPreset::LowBandwidth => {
+                    opts.set("prio_speed", "0");
+                }
+                EncoderPreset::Balanced => {}
+            }
+            match codec {
+                VideoCodec::H264 => opts.set("profile", profile.option_name()),
+                _ => opts.set("profile", "main"),
+            }
+            opts.set("g", &gop);
+        }
+        "h264_vaapi" | "hevc_vaapi" | "vp8_vaapi" | "vp9_vaapi" | "av1_vaapi" => {
+            opts.set("rc_mode", "VBR");
+            // higher is faster, drivers clamp it to the levels they have
+            if let Some(level) = preset.pick(Some("7"), None, Some("2"), Some("1")) {
+                opts.set("compression_level", level);
+            }
+            match codec {
+                VideoCodec::H264 => opts.set("profile", &profile.ffmpeg_profile().to_string()),
+                VideoCodec::H265 => opts.set("profile", "1"),
+                _ => {}
+            }
+            opts.set("g", &gop);
+        }
+        "h264_mf" | "hevc_mf" => {
+            opts.set("scenario", "display_remoting");
+            if let Some(quality) = preset.pick(Some("30"), None, Some("80"), Some("60")) {
+                opts.set("quality", quality);
+            }
+            opts.set("g", &gop);
+        }
+        "libx264" => {
+            // capped CRF
+            opts.set(
+                "preset",
+                preset.pick("ultrafast", "fast", "medium", "medium"),
+            );
+            opts.set("tune", "zerolatency");
+            opts.set("crf", preset.pick("23", "21", "18", "27"));
+            opts.set("sc_threshold", "0");
+            opts.set("profile", profile.option_name());
+            opts.set("level", &level);
+            opts.set("keyint", &gop);
+            opts.set("forced-idr", "1");
+        }
+        "libx265" => {
+            // repeat the parameter sets on every keyframe so viewers can join at any time
+            opts.set(
+                "preset",
+                preset.pick("ultrafast", "fast", "medium", "medium"),
+            );
+            opts.set("tune", "zerolatency");
+            opts.set("crf", preset.pick("26", "24", "21", "30"));
+            opts.set("forced-idr", "1");
+            opts.set(
+                "x265-params",
+                &format!(
+                    "keyint={}:scenecut=0:repeat-headers=1:log-level=warning",
+                    gop
+                ),
+            );
+        }
+        "libsvtav1" => {
+            // low delay prediction with the screen content tools on
+            opts.set("preset", preset.pick("12", "10", "8", "7"));
+            opts.set("svtav1-params", "rc=2:pred-struct=1:scm=1");
+            opts.set("g", &gop);
+        }
+        "libaom-av1" => {
+            // palette and intra block copy come with the screen content tuning
+            opts.set("usage", "realtime");
+            opts.set("cpu-used", preset.pick("10", "9", "7", "6"));
+            opts.set("lag-in-frames", "0");
+            opts.set("row-mt", "1");
+            opts.set("tile-columns", "2");
+            opts.set("enable-palette", "1");
+            opts.set("enable-intrabc", "1");
+            opts.set("aom-params", "tune-content=screen");
+            opts.set("g", &gop);
+        }
+        "librav1e" => {
+            opts.set("speed", preset.pick("10", "10", "8", "7"));
+            opts.set("tiles", "4");
+            opts.set("rav1e-params", "low_latency=true");
+            opts.set("g", &gop);
+        }
+        "libvpx" | "libvpx-vp9" => {
+            // realtime mode without lookahead, tuned for sharp text and flat areas
+            opts.set("deadline", "realtime");
+            opts.set("lag-in-frames", "0");
+            opts.set("error-resilient", "1");
+            opts.set("crf", preset.pick("12", "10", "6", "20"));
+            opts.set("g", &gop);
+            if codec_name == "libvpx" {
+                opts.set("cpu-used", preset.pick("12", "8", "5", "5"));
+                opts.set("screen-content-mode", "1");
+                opts.set("static-thresh", "100");
+            } else {
+                opts.set("cpu-used", preset.pick("9", "8", "6", "6"));
+                opts.set("tune-content", "screen");
+                opts.set("row-mt", "1");
+                opts.set("tile-columns", "2");
+                opts.set("aq-mode", "3");
+            }
+        }
+        _ => {
+            // an encoder given by name, which gets only the generic settings
+            opts.set("g", &gop);
+        }
+    };
+
+    // options from the command line win over the preset, on the encoder they were given for
+    if state.encoder.as_deref() == Some(codec_name) {
+        for option in &state.encoder_options {
+            opts.set(&option.key, &option.value);
+        }
+    }
+
+    debug!(
+        encoder = codec_name,
+        %preset,
+        width, height, bitrate, "Opening encoder"
+    );
+    encoder_ctx
+        .open_with(opts)
+        .map_err(ServerError::ffmpeg("Failed to open encoder"))
+}
@@ -0,0 +1,348 @@
+use std::fmt::Display;
+
+/// H.264 profiles the server encodes, from the least to the most capable.
+///
+/// None of them use B-frames or interlacing, so each stream is also a valid stream of the
+/// constrained variant of its profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum H264Profile {
+    ConstrainedBaseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    /// Every profile, in order of preference.
+    const PREFERRED: &[H264Profile] = &[
+        H264Profile::High,
+        H264Profile::Main,
+        H264Profile::ConstrainedBaseline,
+    ];
+
+    /// Name of the profile in the `profile` option of most FFmpeg encoders.
+    pub fn option_name(self) -> &'static str {
+        match self {
+            H264Profile::ConstrainedBaseline => "baseline",
+            H264Profile::Main => "main",
+            H264Profile::High => "high",
+        }
+    }
+
+    /// FFmpeg's `AV_PROFILE_H264_*` value.
+    pub fn ffmpeg_profile(self) -> i32 {
+        match self {
+            // AV_PROFILE_H264_BASELINE | AV_PROFILE_H264_CONSTRAINED
+            H264Profile::ConstrainedBaseline => 66 | (1 << 9),
+            H264Profile::Main => 77,
+            H264Profile::High => 100,
+        }
+    }
+}
+
+impl Display for H264Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            H264Profile::ConstrainedBaseline => write!(f, "constrained baseline"),
+            H264Profile::Main => write!(f, "main"),
+            H264Profile::High => write!(f, "high"),
+        }
+    }
+}
+
+/// The `profile-level-id` of an H.264 format: profile_idc, the constraint flags and level_idc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ProfileLevelId {
+    profile_idc: u8,
+    constraints: u8,
+    pub level_idc: u8,
+}
+
+impl ProfileLevelId {
+    fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 6 {
+            return None;
+        }
+        let byte = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some(ProfileLevelId {
+            profile_idc: byte(0)?,
+            constraints: byte(2)?,
+            level_idc: byte(4)?,
+        })
+    }
+
+    /// Whether a decoder for this profile can decode a stream encoded with `profile`.
+    fn decodes(self, profile: H264Profile) -> bool {
+        match self.profile_idc {
+            // Baseline and Extended, which decode Constrained Baseline streams
+            66 | 88 => profile == H264Profile::ConstrainedBaseline,
+            77 => profile <= H264Profile::Main,
+            100 => true,
+            // High 10, 4:2:2 and 4:4:4, unless constraint_set3_flag limits them to intra frames
+            110 | 122 | 244 => self.constraints & 0x10 == 0,
+            _ => false,
+        }
+    }
+
+    /// The same format at another level.
+    pub fn with_level(self, level_idc: u8) -> Self {
+        ProfileLevelId { level_idc, ..self }
+    }
+
+    /// The fmtp line announcing this format.
+    pub fn fmtp_line(self) -> String {
+        format!(
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={:02x}{:02x}{:02x}",
+            self.profile_idc, self.constraints, self.level_idc
+        )
+    }
+}
+
+/// Level written as `major.minor`, as the `level` option of FFmpeg encoders takes it.
+pub(crate) fn level_name(level_idc: u8) -> String {
+    format!("{}.{}", level_idc / 10, level_idc % 10)
+}
+
+/// Limits of each level from Table A-1 of H.264: level_idc, macroblocks per second,
+/// macroblocks per frame and the Main profile bitrate in kbit/s.
+const LEVELS: &[(u8, u64, u64, u64)] = &[
+    (10, 1_485, 99, 64),
+    (11, 3_000, 396, 192),
+    (12, 6_000, 396, 384),
+    (13, 11_880, 396, 768),
+    (20, 11_880, 396, 2_000),
+    (21, 19_800, 792, 4_000),
+    (22, 20_250, 1_620, 4_000),
+    (30, 40_500, 1_620, 10_000),
+    (31, 108_000, 3_600, 14_000),
+    (32, 216_000, 5_120, 20_000),
+    (40, 245_760, 8_192, 20_000),
+    (41, 245_760, 8_192, 50_000),
+    (42, 522_240, 8_704, 50_000),
+    (50, 589_824, 22_080, 135_000),
+    (51, 983_040, 36_864, 240_000),
+    (52, 2_073_600, 36_864, 240_000),
+    (60, 4_177_920, 139_264, 240_000),
+    (61, 8_355_840, 139_264, 480_000),
+    (62, 16_711_680, 139_264, 800_000),
+];
+
+/// Lowest level that allows a `width`x`height` stream at `framerate` and up to `bitrate`
+/// bits per second.
+pub(crate) fn level_idc(
+    profile: H264Profile,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    bitrate: u64,
+) -> u8 {
+    let width_mbs = (width as u64).div_ceil(16);
+    let height_mbs = (height as u64).div_ceil(16);
+    let frame_size = width_mbs * height_mbs;
+    // High allows a quarter more bitrate than the other profiles
+    let bitrate_factor = if profile == H264Profile::High {
+        1250
+    } else {
+        1000
+    };
+
+    LEVELS
+        .iter()
+        .find(|&&(_, max_mbps, max_fs, max_br)| {
+            // neither side of the frame may be longer than sqrt(8 * MaxFS) macroblocks
+            frame_size <= max_fs
+                && width_mbs * width_mbs <= 8 * max_fs
+                && height_mbs * height_mbs <= 8 * max_fs
+                && frame_size * framerate as u64 <= max_mbps
+                && bitrate <= max_br * bitrate_factor
+        })
+        .map_or(62, |&(level_idc, ..)| level_idc)
+}
+
+/// H.264 formats offered in an SDP that use packetization mode 1, which is the only one
+/// the payloader produces.
+pub(crate) fn offered_formats(sdp: &str) -> Vec<ProfileLevelId> {
+    let mut payload_types = vec![];
+    for line in sdp.lines() {
+        // a=rtpmap:<payload type> H264/90000
+        let Some((payload_type, encoding)) = line
+            .strip_prefix("a=rtpmap:")
+            .and_then(|rtpmap| rtpmap.split_once(' '))
+        else {
+            continue;
+        };
+        if encoding.to_ascii_uppercase().starts_with("H264/") {
+            payload_types.push(payload_type.trim());
+        }
+    }
+
+    let mut formats = vec![];
+    for line in sdp.lines() {
+        // a=fmtp:<payload type> <parameters>
+        let Some((payload_type, parameters)) = line
+            .strip_prefix("a=fmtp:")
+            .and_then(|fmtp| fmtp.split_once(' '))
+        else {
+            continue;
+        };
+        if !payload_types.contains(&payload_type.trim()) {
+            continue;
+        }
+
+        let parameter = |name: &str| {
+            parameters.split(';').find_map(|parameter| {
+                let (key, value) = parameter.trim().split_once('=')?;
+                key.eq_ignore_ascii_case(name).then_some(value.trim())
+            })
+        };
+        if parameter("packetization-mode") != Some("1") {
+            continue;
+        }
+        // without a profile-level-id the receiver only promises Constrained Baseline 1.0
+        let format = match parameter("profile-level-id") {
+            Some(hex) => ProfileLevelId::parse(hex),
+            None => ProfileLevelId::parse("42e00a"),
+        };
+        formats.extend(format);
+    }
+    formats
+}
+
+/// The most capable profile one of the offered `formats` decodes at the level
+/// `level_idc` gives for it, with that format lowered to the level.
+///
+/// The answer has to keep the profile_idc and constraint flags of the format, which is
+/// how both sides match it up, so the closest one to the stream is picked.
+pub(crate) fn select_profile(
+    formats: &[ProfileLevelId],
+    level_idc: impl Fn(H264Profile) -> u8,
+) -> Option<(H264Profile, ProfileLevelId)> {
+    H264Profile::PREFERRED.iter().find_map(|&profile| {
+        let level_idc = level_idc(profile);
+        formats
+            .iter()
+            .filter(|format| format.decodes(profile) && format.level_idc >= level_idc)
+            .min_by_key(|format| format.profile_idc)
+            .map(|&format| (profile, format.with_level(level_idc)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MBPS: u64 = 1_000_000;
+
+    fn format(hex: &str) -> ProfileLevelId {
+        ProfileLevelId::parse(hex).unwrap()
+    }
+
+    #[test]
+    fn parses_profile_level_id() {
+        assert_eq!(
+            ProfileLevelId::parse("42e01f"),
+            Some(ProfileLevelId {
+                profile_idc: 0x42,
+                constraints: 0xe0,
+                level_idc: 0x1f,
+            })
+        );
+        assert_eq!(format("640C34").level_idc, 52);
+        assert_eq!(ProfileLevelId::parse("42e01"), None);
+        assert_eq!(ProfileLevelId::parse("42e01f0"), None);
+        assert_eq!(ProfileLevelId::parse("42g01f"), None);
+    }
+
+    #[test]
+    fn profiles_decode_the_streams_they_cover() {
+        let baseline = format("42e01f");
+        assert!(baseline.decodes(H264Profile::ConstrainedBaseline));
+        assert!(!baseline.decodes(H264Profile::Main));
+
+        let main = format("4d001f");
+        assert!(main.decodes(H264Profile::Main));
+        assert!(!main.decodes(H264Profile::High));
+
+        assert!(format("640c1f").decodes(H264Profile::High));
+        assert!(format("6e001f").decodes(H264Profile::High));
+        // High 10 Intra
+        assert!(!format("6e101f").decodes(H264Profile::High));
+    }
+
+    #[test]
+    fn fmtp_line_announces_the_level() {
+        assert_eq!(
+            format("42e01f").with_level(42).fmtp_line(),
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e02a"
+        );
+        assert_eq!(level_name(31), "3.1");
+    }
+
+    #[test]
+    fn level_covers_the_frame_size_and_rate() {
+        let main = H264Profile::Main;
+        assert_eq!(level_idc(main, 176, 144, 15, 64_000), 10);
+        assert_eq!(level_idc(main, 176, 144, 16, 64_000), 11);
+
+        // 3600 macroblocks at 30 fps are the limits of 3.1
+        assert_eq!(level_idc(main, 1280, 720, 30, 10 * MBPS), 31);
+        assert_eq!(level_idc(main, 1296, 720, 30, 10 * MBPS), 32);
+        assert_eq!(level_idc(main, 1280, 720, 31, 10 * MBPS), 32);
+
+        assert_eq!(level_idc(main, 1920, 1080, 30, 20 * MBPS), 40);
+        assert_eq!(level_idc(main, 1920, 1080, 60, 20 * MBPS), 42);
+        assert_eq!(level_idc(main, 3840, 2160, 60, 20 * MBPS), 52);
+        assert_eq!(level_idc(main, 7680, 4320, 120, 20 * MBPS), 62);
+    }
+
+    #[test]
+    fn level_covers_long_frames() {
+        // 256 macroblocks wide needs 8 * MaxFS >= 256^2, which 4.0 is the first to allow
+        assert_eq!(level_idc(H264Profile::Main, 4096, 16, 1, 0), 40);
+    }
+
+    #[test]
+    fn level_covers_the_bitrate() {
+        assert_eq!(level_idc(H264Profile::Main, 1280, 720, 30, 14 * MBPS), 31);
+        assert_eq!(
+            level_idc(H264Profile::Main, 1280, 720, 30, 14 * MBPS + 1),
+            32
+        );
+        // High allows a quarter more
+        assert_eq!(level_idc(H264Profile::High, 1280, 720, 30, 17_500_000), 31);
+        assert_eq!(level_idc(H264Profile::Main, 1920, 1080, 30, 25 * MBPS), 41);
+    }
+
+    #[test]
+    fn offered_formats_need_packetization_mode_1() {
+        let sdp = "v=0\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 96 102 104 106\r\n\
+            a=rtpmap:96 VP8/90000\r\n\
+            a=rtpmap:102 H264/90000\r\n\
+            a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n\
+            a=rtpmap:104 H264/90000\r\n\
+            a=fmtp:104 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=640c1f\r\n\
+            a=rtpmap:106 h264/90000\r\n\
+            a=fmtp:106 packetization-mode=1\r\n";
+
+        assert_eq!(
+            offered_formats(sdp),
+            vec![format("42e01f"), format("42e00a")]
+        );
+    }
+
+    #[test]
+    fn selects_the_best_profile_at_the_needed_level() {
+        let formats = [format("42e01f"), format("4d0032"), format("640c1f")];
+
+        assert_eq!(
+            select_profile(&formats, |_| 31),
+            Some((H264Profile::High, format("640c1f")))
+        );
+        assert_eq!(
+            select_profile(&formats, |_| 40),
+            Some((H264Profile::Main, format("4d0028")))
+        );
+        assert_eq!(select_profile(&formats, |_| 51), None);
+        assert_eq!(select_profile(&[], |_| 10), None);
+    }
+}
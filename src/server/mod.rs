@@ -1,61 +1,709 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use anyhow::Result;
-use dialoguer::Select;
-use tokio::sync::{Mutex, broadcast};
+use regex::Regex;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
 use webrtc::{
     data_channel::RTCDataChannel, peer_connection::RTCPeerConnection,
-    track::track_local::track_local_static_sample::TrackLocalStaticSample,
+    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
 };
 use xcap::Monitor;
 
+use self::encoder::StreamFormat;
+use crate::{
+    error::ServerError,
+    shared::{DEFAULT_NACK_BUFFER, ShutdownHandle, VideoCodec},
+};
+
+mod backend;
+mod bitrate;
 mod capture;
+mod change;
+mod encoder;
+mod h264;
 mod pair;
+mod preset;
 mod route;
+mod scaling;
+mod source;
+mod window;
+#[cfg(target_os = "linux")]
+mod x11;
 
-use capture::CaptureDevice;
+pub use backend::CaptureBackend;
+pub use capture::{CaptureDevice, Region};
+pub use encoder::probe_encoders;
+pub use preset::{EncoderOption, EncoderPreset};
+pub use scaling::{OutputSize, ScalingAlgorithm};
+pub use source::VideoSource;
+pub use window::{WindowInfo, list_windows, select_window};
+#[cfg(target_os = "linux")]
+pub use x11::resolve_x11_display;
 
 #[derive(PartialEq, Debug)]
-pub enum ConnectionState {
-    Disconnected,
+pub(crate) enum ConnectionState {
     Connecting,
     Connected,
 }
 
-pub struct AppState {
+pub(crate) type SessionId = u64;
+
+/// One connected viewer.
+pub(crate) struct Session {
+    pub connection: ConnectionState,
+    pub peer_connection: Arc<RTCPeerConnection>,
+    pub format: StreamFormat,
+    pub video_track: Arc<TrackLocalStaticRTP>,
+    pub mouse_channel: Option<Arc<RTCDataChannel>>,
+    /// Bandwidth estimated from this viewer's RTCP feedback, in bits per second.
+    pub bitrate: u64,
+}
+
+pub(crate) struct AppState {
+    pub source: VideoSource,
+    pub capture_backend: CaptureBackend,
+    /// X display the screen is captured on, on Linux.
+    pub x11_display: Option<String>,
     pub device: CaptureDevice,
+    pub window: Option<WindowInfo>,
+    region: RwLock<Region>,
+    /// Size of the encoded frames.
+    stream_size: RwLock<(u32, u32)>,
     pub framerate: u32,
+    /// Codecs the server can encode, in order of preference, each with its encoders
+    /// from the one to use to the last fallback.
+    pub encoders: Vec<(VideoCodec, Vec<String>)>,
+    pub preset: EncoderPreset,
+    /// Encoder given by name, the only one the encoder options are set on.
+    pub encoder: Option<String>,
+    /// Options set on the encoder given by name after the preset.
+    pub encoder_options: Vec<EncoderOption>,
+    pub output_size: Option<OutputSize>,
+    pub scaling: ScalingAlgorithm,
     pub password: Option<String>,
-    pub connection: Mutex<ConnectionState>,
-    pub peer_connection: Mutex<Option<Arc<RTCPeerConnection>>>,
-    pub video_track: Mutex<Option<Arc<TrackLocalStaticSample>>>,
-    pub mouse_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    pub max_viewers: usize,
+    pub max_bitrate: u64,
+    pub nack_buffer: u16,
+    /// Time between frames encoded while the screen does not change, `None` to encode
+    /// every captured frame.
+    pub idle_heartbeat: Option<Duration>,
+    pub sessions: Mutex<HashMap<SessionId, Session>>,
+    next_session_id: AtomicU64,
+    target_bitrate: AtomicU64,
+    keyframe_requested: AtomicBool,
 }
 
 impl AppState {
-    pub fn new(device: CaptureDevice, framerate: u32, password: Option<String>) -> Self {
-        AppState {
+    pub fn new(
+        settings: &ServerSettings,
+        encoders: Vec<(VideoCodec, Vec<String>)>,
+        device: CaptureDevice,
+        window: Option<WindowInfo>,
+        region: Region,
+    ) -> Self {
+        let max_bitrate = settings
+            .max_bitrate
+            .map_or(bitrate::DEFAULT_MAX_BITRATE, |kbps| kbps as u64 * 1000);
+
+        let state = AppState {
+            source: settings.source.clone(),
+            capture_backend: settings.capture_backend,
+            x11_display: settings.x11_display.clone(),
             device,
-            framerate,
-            password,
-            connection: Mutex::new(ConnectionState::Disconnected),
-            peer_connection: Mutex::new(None),
-            video_track: Mutex::new(None),
-            mouse_channel: Mutex::new(None),
+            window,
+            region: RwLock::new(region),
+            stream_size: RwLock::new((0, 0)),
+            framerate: settings.framerate,
+            encoders,
+            preset: settings.preset,
+            encoder: settings.encoder.clone(),
+            encoder_options: settings.encoder_options.clone(),
+            output_size: settings.output_size,
+            scaling: settings.scaling,
+            password: settings.password.clone(),
+            max_viewers: settings.max_viewers,
+            max_bitrate,
+            nack_buffer: settings.nack_buffer,
+            idle_heartbeat: settings.idle_heartbeat,
+            sessions: Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(1),
+            target_bitrate: AtomicU64::new(bitrate::START_BITRATE.min(max_bitrate)),
+            keyframe_requested: AtomicBool::new(false),
+        };
+
+        // viewers that connect before the first frame negotiate for the size it will have
+        let (width, height) = capture::output_dimensions(&state, region.width, region.height);
+        state.set_stream_size(width, height);
+        state
+    }
+
+    /// The captured rectangle in desktop coordinates, which moves with the window in window mode.
+    pub fn region(&self) -> Region {
+        *self.region.read().unwrap()
+    }
+
+    pub fn set_region(&self, region: Region) {
+        *self.region.write().unwrap() = region;
+    }
+
+    pub fn stream_size(&self) -> (u32, u32) {
+        *self.stream_size.read().unwrap()
+    }
+
+    pub fn set_stream_size(&self, width: u32, height: u32) {
+        *self.stream_size.write().unwrap() = (width, height);
+    }
+
+    /// Encoders for `codec`, in the order they are tried.
+    pub fn encoder_names(&self, codec: VideoCodec) -> &[String] {
+        self.encoders
+            .iter()
+            .find(|(encoder_codec, _)| *encoder_codec == codec)
+            .map(|(_, names)| names.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn next_session_id(&self) -> SessionId {
+        self.next_session_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Video tracks of the connected sessions streaming `format`, so packets can be written
+    /// without holding the lock.
+    pub async fn video_tracks(&self, format: StreamFormat) -> Vec<Arc<TrackLocalStaticRTP>> {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .filter(|session| {
+                session.connection == ConnectionState::Connected && session.format == format
+            })
+            .map(|session| session.video_track.clone())
+            .collect()
+    }
+
+    /// Formats used by at least one session, or `None` while the sessions are locked.
+    pub fn session_formats(&self) -> Option<Vec<StreamFormat>> {
+        let sessions = self.sessions.try_lock().ok()?;
+        let mut formats = vec![];
+        for session in sessions.values() {
+            if !formats.contains(&session.format) {
+                formats.push(session.format);
+            }
+        }
+        Some(formats)
+    }
+
+    /// Mouse channels of all sessions that have opened one.
+    pub async fn mouse_channels(&self) -> Vec<Arc<RTCDataChannel>> {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .filter_map(|session| session.mouse_channel.clone())
+            .collect()
+    }
+
+    /// Ask the encoder for a keyframe, after a viewer lost data or joined mid-stream.
+    pub fn request_keyframe(&self) {
+        self.keyframe_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a keyframe was requested since the last call.
+    pub fn take_keyframe_request(&self) -> bool {
+        self.keyframe_requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Bitrate the encoder should run at, so the slowest viewer keeps up.
+    pub fn target_bitrate(&self) -> u64 {
+        self.target_bitrate.load(Ordering::Relaxed)
+    }
+
+    /// Record a viewer's bandwidth estimate and update the encoder target.
+    pub async fn set_session_bitrate(&self, id: SessionId, bitrate: u64) {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get_mut(&id) {
+            session.bitrate = bitrate;
+        }
+        self.update_target_bitrate(&sessions);
+    }
+
+    fn update_target_bitrate(&self, sessions: &HashMap<SessionId, Session>) {
+        let target = sessions
+            .values()
+            .map(|session| session.bitrate)
+            .min()
+            .unwrap_or(bitrate::START_BITRATE)
+            .min(self.max_bitrate);
+        self.target_bitrate.store(target, Ordering::Relaxed);
+    }
+
+    /// Remove a session from the registry and close its peer connection.
+    pub async fn close_session(&self, id: SessionId) {
+        let session = {
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions.remove(&id);
+            self.update_target_bitrate(&sessions);
+            session
+        };
+        if let Some(session) = session {
+            if let Err(err) = session.peer_connection.close().await {
+                warn!(session = id, error = %err, "Failed to close peer connection");
+            }
+            info!(session = id, "Session closed");
+        }
+    }
+
+    /// Close every session, used on shutdown.
+    pub async fn close_all_sessions(&self) {
+        let ids = self
+            .sessions
+            .lock()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<SessionId>>();
+        for id in ids {
+            self.close_session(id).await;
+        }
+    }
+}
+
+/// Resolved server settings, after merging the config file and command line.
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    pub port: u16,
+    pub framerate: u32,
+    pub code: String,
+    pub password: Option<String>,
+    pub hwaccel: bool,
+    /// Codecs to stream with, in order of preference. Each viewer gets the first one it can decode.
+    pub codecs: Vec<VideoCodec>,
+    /// Trade-off the encoders are tuned for.
+    pub preset: EncoderPreset,
+    /// FFmpeg encoder to use instead of picking one, for the codec it produces.
+    pub encoder: Option<String>,
+    /// FFmpeg options set on the `encoder` given by name, overriding the preset.
+    pub encoder_options: Vec<EncoderOption>,
+    /// What to stream, the screen unless a test pattern or video file is given.
+    pub source: VideoSource,
+    /// How the screen is captured.
+    pub capture_backend: CaptureBackend,
+    /// X display to capture on Linux, `$DISPLAY` when not set.
+    pub x11_display: Option<String>,
+    pub monitor: Option<String>,
+    /// Part of the monitor to capture, relative to its top left corner. Without a
+    /// monitor it is relative to the desktop instead.
+    pub region: Option<Region>,
+    /// Window to capture instead of a monitor, by id, title or regex on the title or process.
+    pub window: Option<String>,
+    /// Encode at a smaller size than the capture.
+    pub output_size: Option<OutputSize>,
+    pub scaling: ScalingAlgorithm,
+    /// Upper limit for the adaptive bitrate, in kbit/s.
+    pub max_bitrate: Option<u32>,
+    pub max_viewers: usize,
+    /// Sent packets kept per viewer to retransmit when they are reported lost.
+    pub nack_buffer: u16,
+    /// Time between frames encoded while the screen does not change, `None` to encode
+    /// every captured frame.
+    pub idle_heartbeat: Option<Duration>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            port: 8787,
+            framerate: 60,
+            code: String::from("hello"),
+            password: None,
+            hwaccel: false,
+            codecs: VideoCodec::ALL.to_vec(),
+            preset: EncoderPreset::default(),
+            encoder: None,
+            encoder_options: vec![],
+            source: VideoSource::default(),
+            capture_backend: CaptureBackend::default(),
+            x11_display: None,
+            monitor: None,
+            region: None,
+            window: None,
+            output_size: None,
+            scaling: ScalingAlgorithm::default(),
+            max_bitrate: None,
+            max_viewers: 4,
+            nack_buffer: DEFAULT_NACK_BUFFER,
+            idle_heartbeat: Some(encoder::DEFAULT_IDLE_HEARTBEAT),
+        }
+    }
+}
+
+/// Builder for a display [`Server`].
+///
+/// The monitor is taken from [`ServerBuilder::device`] if given, otherwise it is
+/// picked with the [`ServerBuilder::monitor`] selector, falling back to the first monitor.
+/// A [`ServerBuilder::region`] without either is placed on the desktop, and the monitor
+/// is the one it starts on. [`ServerBuilder::window`] captures a single window instead,
+/// ignoring the monitor and region. A [`ServerBuilder::source`] other than the screen
+/// ignores all of them and needs no desktop.
+///
+/// On Linux, xcap lists and captures monitors and windows on the display in `$DISPLAY`
+/// only, and the builder does not change the environment. A program capturing another
+/// display with [`ServerBuilder::x11_display`] has to set `$DISPLAY` to the same display
+/// itself, before starting any thread, or [`ServerBuilder::build`] fails.
+#[derive(Default)]
+pub struct ServerBuilder {
+    settings: ServerSettings,
+    device: Option<CaptureDevice>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_settings(settings: ServerSettings) -> Self {
+        ServerBuilder {
+            settings,
+            device: None,
+        }
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.settings.port = port;
+        self
+    }
+
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.settings.framerate = framerate;
+        self
+    }
+
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.settings.code = code.into();
+        self
+    }
+
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.settings.password = password;
+        self
+    }
+
+    pub fn hwaccel(mut self, hwaccel: bool) -> Self {
+        self.settings.hwaccel = hwaccel;
+        self
+    }
+
+    /// Codecs to stream with, in order of preference.
+    pub fn codecs(mut self, codecs: Vec<VideoCodec>) -> Self {
+        self.settings.codecs = codecs;
+        self
+    }
+
+    /// Trade-off between delay, quality and bandwidth the encoders are tuned for.
+    pub fn preset(mut self, preset: EncoderPreset) -> Self {
+        self.settings.preset = preset;
+        self
+    }
+
+    /// Use this FFmpeg encoder, such as `h264_nvenc`, for the codec it produces and
+    /// prefer that codec.
+    pub fn encoder(mut self, encoder: impl Into<String>) -> Self {
+        self.settings.encoder = Some(encoder.into());
+        self
+    }
+
+    /// Set an FFmpeg option on the encoder given with [`ServerBuilder::encoder`], overriding
+    /// the preset. Encoders of other codecs, negotiated with other viewers, do not get it.
+    pub fn encoder_option(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.encoder_options.push(EncoderOption {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Stream a test pattern or a looping video file instead of the screen.
+    pub fn source(mut self, source: VideoSource) -> Self {
+        self.settings.source = source;
+        self
+    }
+
+    /// Capture the monitor or region with FFmpeg's input device or with xcap's
+    /// screenshots. Windows are always captured with xcap.
+    pub fn capture_backend(mut self, backend: CaptureBackend) -> Self {
+        self.settings.capture_backend = backend;
+        self
+    }
+
+    /// X display to capture on Linux, such as `:1` for an Xvfb server, instead of `$DISPLAY`.
+    ///
+    /// x11grab and the pointer use this display directly. Monitors and windows are only
+    /// listed on `$DISPLAY`, so unless a device is given and captured with FFmpeg,
+    /// `$DISPLAY` must name the same display or [`ServerBuilder::build`] returns
+    /// [`ServerError::X11Display`].
+    pub fn x11_display(mut self, display: impl Into<String>) -> Self {
+        self.settings.x11_display = Some(display.into());
+        self
+    }
+
+    /// Monitor selector, by index counting from 0, exact name or regex on the name.
+    pub fn monitor(mut self, selector: impl Into<String>) -> Self {
+        self.settings.monitor = Some(selector.into());
+        self
+    }
+
+    /// Capture only this part of the monitor, or of the desktop when no monitor is chosen.
+    pub fn region(mut self, region: Region) -> Self {
+        self.settings.region = Some(region);
+        self
+    }
+
+    /// Capture a single window, by id, exact title or regex on the title or process name.
+    pub fn window(mut self, selector: impl Into<String>) -> Self {
+        self.settings.window = Some(selector.into());
+        self
+    }
+
+    /// Encode at a scale factor of the capture, or fitted into a `WxH` box.
+    pub fn output_size(mut self, output_size: OutputSize) -> Self {
+        self.settings.output_size = Some(output_size);
+        self
+    }
+
+    /// Algorithm used to resize captured frames.
+    pub fn scaling(mut self, scaling: ScalingAlgorithm) -> Self {
+        self.settings.scaling = scaling;
+        self
+    }
+
+    /// Never let the adaptive bitrate go above this many kbit/s.
+    pub fn max_bitrate(mut self, kbps: u32) -> Self {
+        self.settings.max_bitrate = Some(kbps);
+        self
+    }
+
+    /// Maximum number of viewers streaming at the same time.
+    pub fn max_viewers(mut self, max_viewers: usize) -> Self {
+        self.settings.max_viewers = max_viewers;
+        self
+    }
+
+    /// Number of sent packets kept to retransmit lost ones, rounded up to a power of two.
+    pub fn nack_buffer(mut self, packets: u16) -> Self {
+        self.settings.nack_buffer = packets;
+        self
+    }
+
+    /// Encode an unchanged screen only this often, or every captured frame with `None`.
+    pub fn idle_heartbeat(mut self, interval: Option<Duration>) -> Self {
+        self.settings.idle_heartbeat = interval;
+        self
+    }
+
+    /// Capture this device, skipping monitor selection.
+    pub fn device(mut self, device: CaptureDevice) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    pub fn build(mut self) -> Result<Server, ServerError> {
+        if self.settings.framerate == 0 {
+            return Err(ServerError::InvalidFramerate(self.settings.framerate));
+        }
+        if !self.settings.encoder_options.is_empty() && self.settings.encoder.is_none() {
+            return Err(ServerError::EncoderOptionsWithoutEncoder);
+        }
+
+        let size = match &self.settings.source {
+            VideoSource::Screen => None,
+            VideoSource::TestPattern { width, height } => Some((*width, *height)),
+            VideoSource::File(path) => Some(source::file_size(path)?),
+        };
+        if let Some((width, height)) = size {
+            let device = CaptureDevice {
+                index: 0,
+                name: self.settings.source.to_string(),
+                width,
+                height,
+                x: 0,
+                y: 0,
+            };
+
+            return Ok(Server {
+                region: device.bounds(),
+                window: None,
+                settings: self.settings,
+                device,
+                shutdown: ShutdownHandle::new(),
+            });
         }
+
+        // monitors, windows and the capture all have to be on the same display
+        #[cfg(target_os = "linux")]
+        {
+            let display = resolve_x11_display(self.settings.x11_display.as_deref())?;
+            if self.device.is_none()
+                || self.settings.window.is_some()
+                || self.settings.capture_backend == CaptureBackend::Xcap
+            {
+                x11::check_xcap_display(&display)?;
+            }
+            self.settings.x11_display = Some(display);
+        }
+
+        if let Some(selector) = &self.settings.window {
+            let window = select_window(&list_windows()?, selector)?;
+            let devices = list_capture_devices()?;
+            let device = find_device_at(&devices, &window.bounds());
+
+            return Ok(Server {
+                region: window.bounds(),
+                window: Some(window),
+                settings: self.settings,
+                device,
+                shutdown: ShutdownHandle::new(),
+            });
+        }
+
+        let (device, region) = match (self.device, &self.settings.monitor) {
+            (Some(device), _) => {
+                let region = resolve_region(&device, self.settings.region)?;
+                (device, region)
+            }
+            (None, Some(selector)) => {
+                let device = select_capture_device(&list_capture_devices()?, selector)?;
+                let region = resolve_region(&device, self.settings.region)?;
+                (device, region)
+            }
+            (None, None) => {
+                let devices = list_capture_devices()?;
+                match self.settings.region {
+                    Some(region) => resolve_desktop_region(&devices, region)?,
+                    None => (devices[0].clone(), devices[0].bounds()),
+                }
+            }
+        };
+
+        Ok(Server {
+            settings: self.settings,
+            device,
+            window: None,
+            region,
+            shutdown: ShutdownHandle::new(),
+        })
     }
 }
 
-pub async fn run_cli_server(
-    port: u16,
-    framerate: u32,
-    code: String,
-    password: Option<String>,
-    hwaccel: bool,
-) -> Result<()> {
-    let (shutdown_tx, _) = broadcast::channel::<()>(1);
+/// A display server, streaming one monitor to every client that pairs with it.
+pub struct Server {
+    settings: ServerSettings,
+    device: CaptureDevice,
+    window: Option<WindowInfo>,
+    region: Region,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn device(&self) -> &CaptureDevice {
+        &self.device
+    }
 
-    // first select screen
+    /// The captured window, in window mode.
+    pub fn window(&self) -> Option<&WindowInfo> {
+        self.window.as_ref()
+    }
+
+    /// The captured rectangle, in desktop coordinates.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn settings(&self) -> &ServerSettings {
+        &self.settings
+    }
+
+    /// Handle to stop [`Server::run`] from another task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Capture, advertise and serve until the shutdown handle is triggered.
+    pub async fn run(self) -> Result<(), ServerError> {
+        let Server {
+            settings,
+            device,
+            window,
+            region,
+            shutdown,
+        } = self;
+
+        // keep the codecs that can be encoded here, each viewer gets the first it can decode
+        let encoders = encoder::available_encoders(
+            &settings.codecs,
+            settings.hwaccel,
+            settings.encoder.as_deref(),
+        )?;
+        for (codec, names) in &encoders {
+            info!(%codec, encoder = names[0].as_str(), "Codec available");
+        }
+        for option in &settings.encoder_options {
+            info!(%option, "Encoder option");
+        }
+
+        // init app state
+        let state = Arc::new(AppState::new(&settings, encoders, device, window, region));
+
+        // start screen capture
+        let capture_screen_handle =
+            tokio::spawn(capture::capture_screen(state.clone(), shutdown.subscribe()));
+
+        // start mouse capture
+        let capture_mouse_handle =
+            tokio::spawn(capture::capture_mouse(state.clone(), shutdown.subscribe()));
+
+        // start pairing service
+        let pairing_handle = tokio::spawn(pair::start_pairing_service(
+            settings.port,
+            settings.code.clone(),
+            shutdown.subscribe(),
+        ));
+
+        // start warp server
+        let route = route::create_warp_route(settings.port, state.clone());
+        let mut server_shutdown_rx = shutdown.subscribe();
+        warp::serve(route)
+            .bind(([0, 0, 0, 0], settings.port))
+            .await
+            .graceful(async move {
+                let _ = server_shutdown_rx.recv().await;
+            })
+            .run()
+            .await;
+
+        info!("Shutting down...");
+
+        shutdown.shutdown();
+        state.close_all_sessions().await;
+        let shutdown_timeout = tokio::time::Duration::from_secs(3);
+        let _ = tokio::time::timeout(shutdown_timeout, async {
+            tokio::join!(capture_screen_handle, capture_mouse_handle, pairing_handle)
+        })
+        .await;
+
+        Ok(())
+    }
+}
+
+/// Enumerate the monitors that can be captured.
+pub fn list_capture_devices() -> Result<Vec<CaptureDevice>, ServerError> {
     let devices = Monitor::all()?
         .into_iter()
         .enumerate()
@@ -68,63 +716,249 @@ pub async fn run_cli_server(
             y: m.y().unwrap_or_default(),
         })
         .collect::<Vec<CaptureDevice>>();
-    let device_index = Select::new()
-        .with_prompt("Select the virtual screen to use")
-        .items(
-            &devices
-                .iter()
-                .map(|m| format!("{}. {}", m.index + 1, m))
-                .collect::<Vec<String>>(),
-        )
-        .default(0)
-        .interact()?;
-
-    // init app state
-    let state = Arc::new(AppState::new(
-        devices[device_index].to_owned(),
-        framerate,
-        password.clone(),
-    ));
-
-    // start screen capture
-    let capture_screen_handle = tokio::spawn(capture::capture_screen(
-        state.clone(),
-        hwaccel,
-        shutdown_tx.subscribe(),
-    ));
-
-    // start mouse capture
-    let capture_mouse_handle = tokio::spawn(capture::capture_mouse(
-        state.clone(),
-        shutdown_tx.subscribe(),
-    ));
-
-    // start pairing service
-    let pairing_handle = tokio::spawn(pair::start_pairing_service(
-        port,
-        code,
-        shutdown_tx.subscribe(),
-    ));
-
-    // start warp server
-    let route = route::create_warp_route(port, state.clone());
-    warp::serve(route)
-        .bind(([0, 0, 0, 0], port))
-        .await
-        .graceful(async {
-            let _ = tokio::signal::ctrl_c().await;
+
+    if devices.is_empty() {
+        return Err(ServerError::NoMonitors);
+    }
+
+    Ok(devices)
+}
+
+/// Pick a monitor by index, counting from 0 as [`list_capture_devices`] does, exact name or
+/// regular expression on the name.
+pub fn select_capture_device(
+    devices: &[CaptureDevice],
+    selector: &str,
+) -> Result<CaptureDevice, ServerError> {
+    let describe = |devices: &[&CaptureDevice]| {
+        devices
+            .iter()
+            .map(|m| format!("{}. {}", m.index, m))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let not_found = || ServerError::MonitorNotFound {
+        selector: selector.to_string(),
+        available: describe(&devices.iter().collect::<Vec<&CaptureDevice>>()),
+    };
+
+    if let Ok(index) = selector.parse::<usize>() {
+        return devices
+            .iter()
+            .find(|m| m.index == index)
+            .cloned()
+            .ok_or_else(not_found);
+    }
+
+    if let Some(device) = devices.iter().find(|m| m.name == selector) {
+        return Ok(device.clone());
+    }
+
+    let pattern = Regex::new(selector).map_err(|source| ServerError::InvalidMonitorSelector {
+        selector: selector.to_string(),
+        source,
+    })?;
+    let matches = devices
+        .iter()
+        .filter(|m| pattern.is_match(&m.name))
+        .collect::<Vec<&CaptureDevice>>();
+
+    match matches.as_slice() {
+        [device] => Ok((*device).clone()),
+        [] => Err(not_found()),
+        _ => Err(ServerError::AmbiguousMonitor {
+            selector: selector.to_string(),
+            matches: describe(&matches),
+        }),
+    }
+}
+
+/// Place a region given relative to a monitor on the desktop.
+fn resolve_region(device: &CaptureDevice, region: Option<Region>) -> Result<Region, ServerError> {
+    let Some(region) = region else {
+        return Ok(device.bounds());
+    };
+
+    let region = region.offset(device.x, device.y);
+    if !device.bounds().contains(&region) {
+        return Err(ServerError::RegionOutOfBounds {
+            region,
+            bounds: device.bounds(),
+        });
+    }
+
+    Ok(region)
+}
+
+/// Check a region given in desktop coordinates and find the monitor it starts on.
+fn resolve_desktop_region(
+    devices: &[CaptureDevice],
+    region: Region,
+) -> Result<(CaptureDevice, Region), ServerError> {
+    let left = devices.iter().map(|m| m.x).min().unwrap_or_default();
+    let top = devices.iter().map(|m| m.y).min().unwrap_or_default();
+    let right = devices
+        .iter()
+        .map(|m| m.x as i64 + m.width as i64)
+        .max()
+        .unwrap_or_default();
+    let bottom = devices
+        .iter()
+        .map(|m| m.y as i64 + m.height as i64)
+        .max()
+        .unwrap_or_default();
+    let desktop = Region {
+        x: left,
+        y: top,
+        width: (right - left as i64) as u32,
+        height: (bottom - top as i64) as u32,
+    };
+
+    if !desktop.contains(&region) {
+        return Err(ServerError::RegionOutOfBounds {
+            region,
+            bounds: desktop,
+        });
+    }
+
+    Ok((find_device_at(devices, &region), region))
+}
+
+/// The monitor containing the top left corner of a rectangle, or the first monitor.
+fn find_device_at(devices: &[CaptureDevice], region: &Region) -> CaptureDevice {
+    let origin = Region {
+        width: 1,
+        height: 1,
+        ..*region
+    };
+    devices
+        .iter()
+        .find(|m| m.bounds().contains(&origin))
+        .unwrap_or(&devices[0])
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A laptop panel with two external monitors to its right, the last one lower down.
+    fn devices() -> Vec<CaptureDevice> {
+        [
+            ("eDP-1", 1920, 1080, 0, 0),
+            ("DP-1", 2560, 1440, 1920, 0),
+            ("DP-10", 1280, 1024, 4480, 400),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, width, height, x, y))| CaptureDevice {
+            index,
+            name: name.to_string(),
+            width,
+            height,
+            x,
+            y,
         })
-        .run()
-        .await;
+        .collect()
+    }
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
 
-    println!("Shutting down...");
+    #[test]
+    fn selects_monitors_by_index_from_zero() {
+        let devices = devices();
+        assert_eq!(select_capture_device(&devices, "0").unwrap().name, "eDP-1");
+        assert_eq!(select_capture_device(&devices, "2").unwrap().name, "DP-10");
+        assert!(matches!(
+            select_capture_device(&devices, "3"),
+            Err(ServerError::MonitorNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn selects_monitors_by_exact_name_before_regex() {
+        let devices = devices();
+        // "DP-1" also matches "DP-10" as a regex
+        assert_eq!(select_capture_device(&devices, "DP-1").unwrap().index, 1);
+        assert_eq!(select_capture_device(&devices, "DP-10").unwrap().index, 2);
+    }
+
+    #[test]
+    fn selects_monitors_by_regex() {
+        let devices = devices();
+        assert_eq!(select_capture_device(&devices, "^eDP").unwrap().index, 0);
+        assert!(matches!(
+            select_capture_device(&devices, "^DP"),
+            Err(ServerError::AmbiguousMonitor { .. })
+        ));
+        assert!(matches!(
+            select_capture_device(&devices, "HDMI"),
+            Err(ServerError::MonitorNotFound { .. })
+        ));
+        assert!(matches!(
+            select_capture_device(&devices, "DP-("),
+            Err(ServerError::InvalidMonitorSelector { .. })
+        ));
+    }
+
+    #[test]
+    fn places_monitor_regions_on_the_desktop() {
+        let device = &devices()[1];
+        assert_eq!(resolve_region(device, None).unwrap(), device.bounds());
+        assert_eq!(
+            resolve_region(device, Some(region(0, 0, 1280, 1440))).unwrap(),
+            region(1920, 0, 1280, 1440)
+        );
+        assert!(matches!(
+            resolve_region(device, Some(region(1280, 0, 1281, 1440))),
+            Err(ServerError::RegionOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            resolve_region(device, Some(region(-1, 0, 100, 100))),
+            Err(ServerError::RegionOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn finds_the_monitor_a_desktop_region_starts_on() {
+        let devices = devices();
+        let (device, placed) =
+            resolve_desktop_region(&devices, region(1920, 0, 1280, 1024)).unwrap();
+        assert_eq!(device.index, 1);
+        assert_eq!(placed, region(1920, 0, 1280, 1024));
+
+        // a region may span monitors
+        let (device, _) = resolve_desktop_region(&devices, region(4000, 500, 1000, 500)).unwrap();
+        assert_eq!(device.index, 1);
 
-    let _ = shutdown_tx.send(());
-    let shutdown_timeout = tokio::time::Duration::from_secs(3);
-    let _ = tokio::time::timeout(shutdown_timeout, async {
-        tokio::join!(capture_screen_handle, capture_mouse_handle, pairing_handle)
-    })
-    .await;
+        // the corner below the laptop panel is on the desktop but on no monitor
+        let (device, _) = resolve_desktop_region(&devices, region(0, 1200, 100, 100)).unwrap();
+        assert_eq!(device.index, 0);
+    }
 
-    Ok(())
+    #[test]
+    fn rejects_desktop_regions_outside_every_monitor() {
+        let devices = devices();
+        for outside in [
+            region(-1, 0, 100, 100),
+            region(0, 0, 5761, 100),
+            region(4480, 1400, 100, 100),
+        ] {
+            assert!(
+                matches!(
+                    resolve_desktop_region(&devices, outside),
+                    Err(ServerError::RegionOutOfBounds { .. })
+                ),
+                "{}",
+                outside
+            );
+        }
+    }
 }
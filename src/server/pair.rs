@@ -1,14 +1,16 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
 use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
 use tokio::sync::broadcast;
+use tracing::{error, info};
+
+use crate::error::ServerError;
 
 pub async fn start_pairing_service(
     port: u16,
     code: String,
     mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<()> {
+) -> Result<(), ServerError> {
     let mdns = ServiceDaemon::new()?;
     mdns.disable_interface(IfKind::IPv6)?;
 
@@ -30,16 +32,16 @@ pub async fn start_pairing_service(
     .enable_addr_auto();
 
     mdns.register(service_info).map_err(|e| {
-        eprintln!("Failed to register service: {}", e);
+        error!(error = %e, "Failed to register service");
         e
     })?;
-    println!("Pairing service started. Advertised as '{}'", service_name);
+    info!(service = service_name, "Pairing service started");
 
     // wait for shutdown signal
     let _ = shutdown_rx.recv().await;
 
     mdns.shutdown()?;
-    println!("Shutting down pairing service...");
+    info!("Shutting down pairing service...");
 
     Ok(())
 }
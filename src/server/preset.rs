@@ -0,0 +1,151 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::Deserialize;
+
+/// Trade-off the encoders are tuned for.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncoderPreset {
+    /// Fastest settings, for the lowest delay on a fast network.
+    Latency,
+    /// Fast settings with good quality.
+    #[default]
+    Balanced,
+    /// Slower settings for sharper frames, when the machine has time to spare.
+    Quality,
+    /// Slower settings and stronger compression, for slow or metered networks.
+    LowBandwidth,
+}
+
+impl EncoderPreset {
+    /// The value for this preset, out of one for each.
+    pub(crate) fn pick<T>(self, latency: T, balanced: T, quality: T, low_bandwidth: T) -> T {
+        match self {
+            EncoderPreset::Latency => latency,
+            EncoderPreset::Balanced => balanced,
+            EncoderPreset::Quality => quality,
+            EncoderPreset::LowBandwidth => low_bandwidth,
+        }
+    }
+}
+
+impl Display for EncoderPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderPreset::Latency => write!(f, "latency"),
+            EncoderPreset::Balanced => write!(f, "balanced"),
+            EncoderPreset::Quality => write!(f, "quality"),
+            EncoderPreset::LowBandwidth => write!(f, "low-bandwidth"),
+        }
+    }
+}
+
+impl FromStr for EncoderPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "latency" => Ok(EncoderPreset::Latency),
+            "balanced" => Ok(EncoderPreset::Balanced),
+            "quality" => Ok(EncoderPreset::Quality),
+            "low-bandwidth" => Ok(EncoderPreset::LowBandwidth),
+            _ => Err(format!(
+                "invalid encoder preset '{}', expected one of: latency, balanced, quality, low-bandwidth",
+                s
+            )),
+        }
+    }
+}
+
+/// An FFmpeg encoder option, set after the preset so it overrides it.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct EncoderOption {
+    pub key: String,
+    pub value: String,
+}
+
+impl Display for EncoderOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+impl FromStr for EncoderOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(EncoderOption {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(format!(
+                "invalid encoder option '{}', expected key=value",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for EncoderOption {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_presets() {
+        for preset in [
+            EncoderPreset::Latency,
+            EncoderPreset::Balanced,
+            EncoderPreset::Quality,
+            EncoderPreset::LowBandwidth,
+        ] {
+            assert_eq!(preset.to_string().parse(), Ok(preset));
+        }
+        assert_eq!("Low-Bandwidth".parse(), Ok(EncoderPreset::LowBandwidth));
+    }
+
+    #[test]
+    fn rejects_unknown_presets() {
+        for s in ["", "fast", "low_bandwidth", " latency"] {
+            let err = s.parse::<EncoderPreset>().unwrap_err();
+            assert!(err.starts_with("invalid encoder preset"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn parses_encoder_options() {
+        assert_eq!(
+            " crf = 23 ".parse(),
+            Ok(EncoderOption {
+                key: "crf".to_string(),
+                value: "23".to_string(),
+            })
+        );
+        assert_eq!(
+            "x264-params=keyint=60"
+                .parse::<EncoderOption>()
+                .map(|o| o.value),
+            Ok("keyint=60".to_string())
+        );
+        assert_eq!(
+            "tune=".parse::<EncoderOption>().map(|o| o.value),
+            Ok(String::new())
+        );
+    }
+
+    #[test]
+    fn rejects_encoder_options_without_a_key() {
+        for s in ["", "crf", "=23", " =23"] {
+            assert!(s.parse::<EncoderOption>().is_err(), "{}", s);
+        }
+    }
+}
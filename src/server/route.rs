@@ -1,30 +1,63 @@
-use anyhow::Result;
 use base64::{Engine as _, engine::general_purpose};
-use std::sync::Arc;
-use warp::Filter;
+use std::{convert::Infallible, sync::Arc};
+use tracing::{debug, error, info, warn};
+use warp::{Filter, http::StatusCode};
 use webrtc::{
-    api::media_engine::MIME_TYPE_H264,
     peer_connection::{
         peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription,
     },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
-    track::track_local::{TrackLocal, track_local_static_sample::TrackLocalStaticSample},
+    track::track_local::{TrackLocal, track_local_static_rtp::TrackLocalStaticRTP},
 };
 
-use super::{AppState, ConnectionState};
-use crate::shared::{SdpData, create_peer_connection};
+use super::{
+    AppState, ConnectionState, Session, SessionId, bitrate::BandwidthEstimator,
+    encoder::StreamFormat, h264,
+};
+use crate::shared::{ErrorResponse, SdpData, StreamInfo, VideoCodec, create_peer_connection};
 
+/// Errors returned by the SDP endpoint, rendered as JSON by [`handle_rejection`].
 #[derive(Debug)]
-#[allow(dead_code)]
-struct ErrorMessage(pub String);
+enum ApiError {
+    BadOffer(String),
+    Unauthorized,
+    Conflict(String),
+    NoCommonCodec(String),
+    Internal(String),
+}
 
-impl warp::reject::Reject for ErrorMessage {}
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadOffer(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::NoCommonCodec(_) => StatusCode::NOT_ACCEPTABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::BadOffer(msg) => format!("Invalid offer: {}", msg),
+            ApiError::Unauthorized => "Invalid password".to_string(),
+            ApiError::Conflict(msg) => msg.clone(),
+            ApiError::NoCommonCodec(msg) => format!("No common video codec: {}", msg),
+            ApiError::Internal(msg) => format!("Internal server error: {}", msg),
+        }
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
 
 pub fn create_warp_route(
     port: u16,
     state: Arc<AppState>,
-) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(vec!["content-type"])
@@ -35,9 +68,10 @@ pub fn create_warp_route(
         .and(warp::body::json::<SdpData>())
         .and(with_app_state(state.clone()))
         .and_then(sdp_handler)
+        .recover(handle_rejection)
         .with(cors);
 
-    println!("Starting server on port {}", port);
+    info!(port, "Starting server");
     route
 }
 
@@ -47,127 +81,310 @@ fn with_app_state(
     warp::any().map(move || state.clone())
 }
 
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let (status, message) = if let Some(api_error) = err.find::<ApiError>() {
+        (api_error.status(), api_error.message())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found".to_string())
+    } else if let Some(body_error) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid request body: {}", body_error),
+        )
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed".to_string(),
+        )
+    } else {
+        error!(rejection = ?err, "Unhandled rejection");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_string(),
+        )
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorResponse { error: message }),
+        status,
+    ))
+}
+
 async fn sdp_handler(
     sdp_data: SdpData,
     state: Arc<AppState>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     // verify password if set
-    if let Some(password) = &state.password {
-        if password != &sdp_data.password.unwrap_or_default() {
-            eprintln!("Invalid password attempt");
-            return Err(warp::reject::custom(ErrorMessage(
-                "Invalid password".to_string(),
-            )));
+    if let Some(password) = &state.password
+        && password != &sdp_data.password.unwrap_or_default()
+    {
+        warn!("Invalid password attempt");
+        return Err(warp::reject::custom(ApiError::Unauthorized));
+    }
+
+    let offer = general_purpose::STANDARD
+        .decode(&sdp_data.sdp)
+        .map_err(|e| ApiError::BadOffer(e.to_string()))
+        .and_then(|offer_bytes| {
+            serde_json::from_slice::<RTCSessionDescription>(&offer_bytes)
+                .map_err(|e| ApiError::BadOffer(e.to_string()))
+        })
+        .map_err(warp::reject::custom)?;
+
+    let session_id = state.next_session_id();
+    match negotiate_session(session_id, offer, &state).await {
+        Ok(response) => {
+            info!(session = session_id, "Session negotiated");
+            Ok(warp::reply::json(&response))
+        }
+        Err(err) => {
+            error!(
+                session = session_id,
+                error = err.message(),
+                "Failed to negotiate session"
+            );
+            state.close_session(session_id).await;
+            Err(warp::reject::custom(err))
         }
     }
+}
 
-    // if already connected or connecting, reject new connection
-    if let Ok(conn_state) = state.connection.try_lock() {
-        if *conn_state != ConnectionState::Disconnected {
-            println!("Connection already in progress or established");
-            return Err(warp::reject::custom(ErrorMessage(
-                "Connection already in progress or established".to_string(),
-            )));
+/// Build the peer connection for an offer, register it as a session and return the answer.
+///
+/// The session is registered as soon as its peer connection exists, so
+/// [`AppState::close_session`] can tear it down if a later step fails.
+async fn negotiate_session(
+    session_id: SessionId,
+    offer: RTCSessionDescription,
+    state: &Arc<AppState>,
+) -> Result<SdpData, ApiError> {
+    // H.264 only counts when the viewer decodes one of the profiles we encode, at the
+    // level the stream needs
+    let h264_formats = h264::offered_formats(&offer.sdp);
+    let (width, height) = state.stream_size();
+    let h264_offer = h264::select_profile(&h264_formats, |profile| {
+        h264::level_idc(profile, width, height, state.framerate, state.max_bitrate)
+    });
+    let mut offered = VideoCodec::from_sdp(&offer.sdp);
+    if h264_offer.is_none() && offered.contains(&VideoCodec::H264) {
+        if h264::select_profile(&h264_formats, |_| 0).is_some() {
+            warn!(
+                session = session_id,
+                width,
+                height,
+                framerate = state.framerate,
+                "Viewer offers a lower H.264 level than the stream needs"
+            );
+        } else {
+            debug!(
+                session = session_id,
+                "Viewer offers no H.264 profile the server encodes"
+            );
         }
-    } else {
-        return Err(warp::reject::custom(ErrorMessage(
-            "Connection busy".to_string(),
-        )));
+        offered.retain(|&codec| codec != VideoCodec::H264);
     }
 
-    let offer_bytes = general_purpose::STANDARD.decode(sdp_data.sdp).unwrap();
-    let offer = serde_json::from_slice::<RTCSessionDescription>(&offer_bytes).unwrap();
+    // pick the first codec we can encode that the viewer can decode
+    let (codec, encoder) = state
+        .encoders
+        .iter()
+        .find(|(codec, _)| offered.contains(codec))
+        .map(|(codec, names)| (*codec, names[0].clone()))
+        .ok_or_else(|| {
+            ApiError::NoCommonCodec(format!(
+                "the server encodes {} and the viewer decodes {}",
+                codec_list(state.encoders.iter().map(|(codec, _)| *codec)),
+                codec_list(offered.iter().copied()),
+            ))
+        })?;
+
+    // answer H.264 with the profile and level the stream is actually encoded at
+    let (format, sdp_fmtp_line) = match h264_offer.filter(|_| codec == VideoCodec::H264) {
+        Some((profile, answer_format)) => {
+            let format = StreamFormat {
+                codec,
+                h264_profile: Some(profile),
+            };
+            (format, answer_format.fmtp_line())
+        }
+        None => {
+            let format = StreamFormat {
+                codec,
+                h264_profile: None,
+            };
+            (format, codec.sdp_fmtp_line())
+        }
+    };
+    info!(session = session_id, %format, encoder = encoder.as_str(), "Selected codec");
 
     // create new peer connection
-    let pc = create_peer_connection().await.unwrap();
+    let pc = create_peer_connection(&[(codec, sdp_fmtp_line.clone())], state.nack_buffer)
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
 
     // prepare local video track
-    let video_track = Arc::new(TrackLocalStaticSample::new(
+    let video_track = Arc::new(TrackLocalStaticRTP::new(
         RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_H264.to_owned(),
+            mime_type: codec.mime_type().to_owned(),
+            clock_rate: 90000,
+            sdp_fmtp_line,
             ..Default::default()
         },
         "video".to_owned(),
         "webrtc-rs".to_owned(),
     ));
 
+    let mut estimator = BandwidthEstimator::new(state.max_bitrate);
+
+    // reserve a slot, rejecting the viewer when the server is full
     {
-        let mut peer_connection = state.peer_connection.lock().await;
-        let mut video_track_state = state.video_track.lock().await;
-        *peer_connection = Some(pc.clone());
-        *video_track_state = Some(video_track.clone());
+        let mut sessions = state.sessions.lock().await;
+        if sessions.len() >= state.max_viewers {
+            drop(sessions);
+            let _ = pc.close().await;
+            warn!(
+                max_viewers = state.max_viewers,
+                "Rejecting viewer, server is full"
+            );
+            return Err(ApiError::Conflict(format!(
+                "Server is full ({} viewers connected)",
+                state.max_viewers
+            )));
+        }
+        sessions.insert(
+            session_id,
+            Session {
+                connection: ConnectionState::Connecting,
+                format,
+                peer_connection: pc.clone(),
+                video_track: video_track.clone(),
+                mouse_channel: None,
+                bitrate: estimator.estimate(),
+            },
+        );
     }
+    state
+        .set_session_bitrate(session_id, estimator.estimate())
+        .await;
 
     // connect mouse data channel
     let state_clone_for_dc = state.clone();
     pc.on_data_channel(Box::new(move |dc| {
         if dc.label() == "mouse" {
-            println!("Mouse data channel opened");
+            info!(session = session_id, "Mouse data channel opened");
 
             let state_clone = state_clone_for_dc.clone();
             let dc_clone = dc.clone();
             Box::pin(async move {
-                *state_clone.mouse_channel.lock().await = Some(dc_clone);
+                if let Some(session) = state_clone.sessions.lock().await.get_mut(&session_id) {
+                    session.mouse_channel = Some(dc_clone);
+                }
             })
         } else {
             Box::pin(async {})
         }
     }));
 
-    let _ = pc
+    let rtp_sender = pc
         .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
         .await
-        .unwrap();
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    // read incoming RTCP packets for keyframe requests and to estimate the
+    // viewer's bandwidth, until the peer connection is closed
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+            let keyframe_requested = packets.iter().any(|packet| {
+                let packet = packet.as_any();
+                packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>()
+            });
+            if keyframe_requested {
+                debug!(session = session_id, "Viewer requested a keyframe");
+                state_clone.request_keyframe();
+            }
 
-    // read incoming RTCP packets
-    // tokio::spawn(async move {
-    //     let mut rtcp_buf = vec![0u8; 1500];
-    //     while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-    // });
+            if estimator.on_rtcp(&packets) {
+                debug!(
+                    session = session_id,
+                    bitrate = estimator.estimate(),
+                    "Bandwidth estimate changed"
+                );
+                state_clone
+                    .set_session_bitrate(session_id, estimator.estimate())
+                    .await;
+            }
+        }
+    });
 
     // set handler for peer connection state
     let state_clone = state.clone();
     pc.on_peer_connection_state_change(Box::new(move |s| {
-        println!("Peer connection State has changed: {}", s);
+        info!(session = session_id, state = %s, "Peer connection state has changed");
         let state_clone = state_clone.clone();
         Box::pin(async move {
-            if s == RTCPeerConnectionState::Disconnected
-                || s == RTCPeerConnectionState::Closed
-                || s == RTCPeerConnectionState::Failed
-            {
-                *state_clone.connection.lock().await = ConnectionState::Disconnected;
-                *state_clone.peer_connection.lock().await = None;
-                *state_clone.video_track.lock().await = None;
-                *state_clone.mouse_channel.lock().await = None;
+            match s {
+                RTCPeerConnectionState::Connected => {
+                    let mut sessions = state_clone.sessions.lock().await;
+                    if let Some(session) = sessions.get_mut(&session_id) {
+                        session.connection = ConnectionState::Connected;
+                    }
+                    info!(
+                        session = session_id,
+                        viewers = sessions.len(),
+                        "Viewer connected"
+                    );
+
+                    // the new viewer can only start decoding from a keyframe
+                    state_clone.request_keyframe();
+                }
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Closed
+                | RTCPeerConnectionState::Failed => {
+                    // closing from inside this handler would deadlock on it, so hand it off
+                    tokio::spawn(async move { state_clone.close_session(session_id).await });
+                }
+                _ => {}
             }
         })
     }));
 
-    *state.connection.lock().await = ConnectionState::Connecting;
-
     // set remote description
-    pc.set_remote_description(offer).await.unwrap();
-    let answer = pc.create_answer(None).await.unwrap();
+    pc.set_remote_description(offer)
+        .await
+        .map_err(|e| ApiError::BadOffer(e.to_string()))?;
+    let answer = pc
+        .create_answer(None)
+        .await
+        .map_err(|e| ApiError::BadOffer(e.to_string()))?;
     let mut gather_complete = pc.gathering_complete_promise().await;
-    pc.set_local_description(answer).await.unwrap();
+    pc.set_local_description(answer)
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
     let _ = gather_complete.recv().await;
 
-    if let Some(local_desc) = pc.local_description().await {
-        let json_str = serde_json::to_string(&local_desc).unwrap();
-        let b64 = general_purpose::STANDARD.encode(json_str);
-        let response = SdpData {
-            sdp: b64,
-            password: None,
-        };
+    let local_desc = pc
+        .local_description()
+        .await
+        .ok_or_else(|| ApiError::Internal("Failed to get local description".to_string()))?;
+    let json_str =
+        serde_json::to_string(&local_desc).map_err(|e| ApiError::Internal(e.to_string()))?;
+    let b64 = general_purpose::STANDARD.encode(json_str);
 
-        *state.connection.lock().await = ConnectionState::Connected;
-        println!("Peer connected successfully");
+    Ok(SdpData {
+        sdp: b64,
+        password: None,
+        stream: Some(StreamInfo { codec, encoder }),
+    })
+}
 
-        Ok(warp::reply::json(&response))
+fn codec_list(codecs: impl Iterator<Item = VideoCodec>) -> String {
+    let codecs = codecs
+        .map(|codec| codec.to_string())
+        .collect::<Vec<String>>();
+    if codecs.is_empty() {
+        "none".to_string()
     } else {
-        eprintln!("Failed to get local description");
-        return Err(warp::reject::custom(ErrorMessage(
-            "Failed to get local description".to_string(),
-        )));
+        codecs.join(", ")
     }
 }
@@ -0,0 +1,195 @@
+use std::{fmt::Display, str::FromStr};
+
+use ffmpeg_next as ffmpeg;
+use serde::Deserialize;
+
+/// Size to encode at, as a scale factor of the capture or a box the capture is fitted into.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub enum OutputSize {
+    Scale(f64),
+    Fit { width: u32, height: u32 },
+}
+
+impl OutputSize {
+    /// Encoded size for a capture of `width`x`height`, keeping the aspect ratio.
+    ///
+    /// The result is never larger than the capture and always even, as YUV 4:2:0 requires.
+    pub fn apply(&self, width: u32, height: u32) -> (u32, u32) {
+        let factor = match *self {
+            OutputSize::Scale(factor) => factor,
+            OutputSize::Fit {
+                width: max_width,
+                height: max_height,
+            } => (max_width as f64 / width as f64).min(max_height as f64 / height as f64),
+        }
+        .min(1.0);
+
+        let scaled = |size: u32| ((size as f64 * factor).round() as u32).max(2) & !1;
+        (scaled(width), scaled(height))
+    }
+}
+
+impl Display for OutputSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputSize::Scale(factor) => write!(f, "{}", factor),
+            OutputSize::Fit { width, height } => write!(f, "{}x{}", width, height),
+        }
+    }
+}
+
+impl FromStr for OutputSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid output size '{}', expected WxH or a scale factor",
+                s
+            )
+        };
+
+        if let Some((width, height)) = s.split_once(['x', 'X']) {
+            let width = width.trim().parse::<u32>().map_err(|_| invalid())?;
+            let height = height.trim().parse::<u32>().map_err(|_| invalid())?;
+            if width < 2 || height < 2 {
+                return Err(invalid());
+            }
+            return Ok(OutputSize::Fit { width, height });
+        }
+
+        match s.trim().parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor <= 1.0 => Ok(OutputSize::Scale(factor)),
+            Ok(_) => Err(format!(
+                "invalid scale factor '{}', expected a value above 0 and up to 1",
+                s
+            )),
+            Err(_) => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for OutputSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Algorithm used to convert and resize captured frames.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScalingAlgorithm {
+    #[default]
+    FastBilinear,
+    Bilinear,
+    Bicubic,
+    Point,
+    Area,
+    Lanczos,
+    Spline,
+}
+
+impl ScalingAlgorithm {
+    pub(crate) fn as_flags(self) -> ffmpeg::software::scaling::Flags {
+        use ffmpeg::software::scaling::Flags;
+
+        match self {
+            ScalingAlgorithm::FastBilinear => Flags::FAST_BILINEAR,
+            ScalingAlgorithm::Bilinear => Flags::BILINEAR,
+            ScalingAlgorithm::Bicubic => Flags::BICUBIC,
+            ScalingAlgorithm::Point => Flags::POINT,
+            ScalingAlgorithm::Area => Flags::AREA,
+            ScalingAlgorithm::Lanczos => Flags::LANCZOS,
+            ScalingAlgorithm::Spline => Flags::SPLINE,
+        }
+    }
+}
+
+impl FromStr for ScalingAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast-bilinear" => Ok(ScalingAlgorithm::FastBilinear),
+            "bilinear" => Ok(ScalingAlgorithm::Bilinear),
+            "bicubic" => Ok(ScalingAlgorithm::Bicubic),
+            "point" => Ok(ScalingAlgorithm::Point),
+            "area" => Ok(ScalingAlgorithm::Area),
+            "lanczos" => Ok(ScalingAlgorithm::Lanczos),
+            "spline" => Ok(ScalingAlgorithm::Spline),
+            _ => Err(format!(
+                "invalid scaling algorithm '{}', expected one of: fast-bilinear, bilinear, bicubic, point, area, lanczos, spline",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_output_sizes() {
+        assert_eq!(
+            "1280x720".parse(),
+            Ok(OutputSize::Fit {
+                width: 1280,
+                height: 720,
+            })
+        );
+        assert_eq!(
+            " 640 X 480 ".parse(),
+            Ok(OutputSize::Fit {
+                width: 640,
+                height: 480,
+            })
+        );
+        assert_eq!("0.5".parse(), Ok(OutputSize::Scale(0.5)));
+        assert_eq!("1".parse(), Ok(OutputSize::Scale(1.0)));
+    }
+
+    #[test]
+    fn rejects_invalid_output_sizes() {
+        for s in [
+            "",
+            "x",
+            "1280x",
+            "1x720",
+            "-1x720",
+            "1280x720x2",
+            "half",
+            "0",
+            "1.5",
+            "-0.5",
+        ] {
+            assert!(s.parse::<OutputSize>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn output_size_keeps_the_aspect_ratio_and_even_sizes() {
+        let fit = OutputSize::Fit {
+            width: 1280,
+            height: 1280,
+        };
+        assert_eq!(fit.apply(1920, 1080), (1280, 720));
+        // never upscales
+        assert_eq!(fit.apply(800, 600), (800, 600));
+        assert_eq!(OutputSize::Scale(0.5).apply(1366, 766), (682, 382));
+        assert_eq!(OutputSize::Scale(0.001).apply(1920, 1080), (2, 2));
+    }
+
+    #[test]
+    fn parses_scaling_algorithms() {
+        assert_eq!("Lanczos".parse(), Ok(ScalingAlgorithm::Lanczos));
+        assert_eq!("fast-bilinear".parse(), Ok(ScalingAlgorithm::FastBilinear));
+        for s in ["", "fast_bilinear", "nearest", "bilinear "] {
+            let err = s.parse::<ScalingAlgorithm>().unwrap_err();
+            assert!(err.starts_with("invalid scaling algorithm"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ffmpeg_next as ffmpeg;
+use serde::Deserialize;
+
+use crate::{error::ServerError, shared::init_ffmpeg};
+
+/// Size of the test pattern when none is given.
+const DEFAULT_TEST_PATTERN_SIZE: (u32, u32) = (1920, 1080);
+
+/// What the server streams: the screen, or a video made up without one.
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(try_from = "String")]
+pub enum VideoSource {
+    /// A monitor, region or window of the desktop.
+    #[default]
+    Screen,
+    /// FFmpeg's `testsrc` pattern, with a running clock, at this size.
+    TestPattern { width: u32, height: u32 },
+    /// A video file, played in a loop.
+    File(PathBuf),
+}
+
+impl VideoSource {
+    pub fn is_screen(&self) -> bool {
+        *self == VideoSource::Screen
+    }
+}
+
+/// FFmpeg's `testsrc` pattern at `framerate`, produced as fast as it is read.
+pub(super) fn open_test_pattern(
+    width: u32,
+    height: u32,
+    framerate: u32,
+) -> Result<ffmpeg::format::context::Input, ServerError> {
+    // find input device
+    let input_device = ffmpeg::device::input::video()
+        .into_iter()
+        .find(|d| d.name() == "lavfi")
+        .ok_or(ServerError::Capture(
+            "lavfi input device not found".to_string(),
+        ))?;
+
+    // the filter graph is the device path
+    let video_path = format!("testsrc=size={}x{}:rate={}", width, height, framerate);
+
+    let ictx = ffmpeg::format::open_with(&video_path, &input_device, ffmpeg::Dictionary::new())
+        .map_err(ServerError::ffmpeg("Failed to open test pattern"))?;
+    Ok(ictx.input())
+}
+
+/// A video file, read as fast as it is decoded.
+pub(super) fn open_file(path: &Path) -> Result<ffmpeg::format::context::Input, ServerError> {
+    ffmpeg::format::input(path).map_err(|e| {
+        ServerError::Capture(format!(
+            "Failed to open video file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Size of the frames of a video file.
+pub(super) fn file_size(path: &Path) -> Result<(u32, u32), ServerError> {
+    init_ffmpeg().map_err(ServerError::ffmpeg("Failed to initialize FFmpeg"))?;
+    let input = open_file(path)?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or_else(|| ServerError::Capture(format!("No video stream in {}", path.display())))?;
+    let decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+        .and_then(|context| context.decoder().video())
+        .map_err(ServerError::ffmpeg(
+            "Failed to read the size of the video file",
+        ))?;
+    Ok((decoder.width(), decoder.height()))
+}
+
+impl Display for VideoSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoSource::Screen => write!(f, "screen"),
+            VideoSource::TestPattern { width, height } => {
+                write!(f, "testsrc:{}x{}", width, height)
+            }
+            VideoSource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for VideoSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid source '{}', expected screen, testsrc[:WxH] or file:<path>",
+                s
+            )
+        };
+
+        if s.eq_ignore_ascii_case("screen") {
+            return Ok(VideoSource::Screen);
+        }
+
+        if let Some(path) = s.strip_prefix("file:") {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(VideoSource::File(PathBuf::from(path)));
+        }
+
+        let Some(size) = s.strip_prefix("testsrc") else {
+            return Err(invalid());
+        };
+        let (width, height) = match size.strip_prefix(':') {
+            Some(size) => {
+                let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+                let width = width.trim().parse::<u32>().map_err(|_| invalid())?;
+                let height = height.trim().parse::<u32>().map_err(|_| invalid())?;
+                if width < 2 || height < 2 {
+                    return Err(invalid());
+                }
+                (width, height)
+            }
+            None if size.is_empty() => DEFAULT_TEST_PATTERN_SIZE,
+            None => return Err(invalid()),
+        };
+        Ok(VideoSource::TestPattern { width, height })
+    }
+}
+
+impl TryFrom<String> for VideoSource {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!("screen".parse(), Ok(VideoSource::Screen));
+        assert_eq!("SCREEN".parse(), Ok(VideoSource::Screen));
+        assert_eq!(
+            "testsrc".parse(),
+            Ok(VideoSource::TestPattern {
+                width: 1920,
+                height: 1080,
+            })
+        );
+        assert_eq!(
+            "testsrc:1280x720".parse(),
+            Ok(VideoSource::TestPattern {
+                width: 1280,
+                height: 720,
+            })
+        );
+        assert_eq!(
+            "file:clips/demo.mp4".parse(),
+            Ok(VideoSource::File(PathBuf::from("clips/demo.mp4")))
+        );
+        // only the first colon separates the path
+        assert_eq!(
+            "file:C:\\demo.mp4".parse(),
+            Ok(VideoSource::File(PathBuf::from("C:\\demo.mp4")))
+        );
+    }
+
+    #[test]
+    fn sources_round_trip_through_display() {
+        for source in [
+            VideoSource::Screen,
+            VideoSource::TestPattern {
+                width: 640,
+                height: 480,
+            },
+            VideoSource::File(PathBuf::from("demo.mkv")),
+        ] {
+            assert_eq!(source.to_string().parse(), Ok(source));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        for s in [
+            "",
+            "desktop",
+            "file:",
+            "demo.mp4",
+            "testsrc:",
+            "testsrc:1280",
+            "testsrc:1280x",
+            "testsrc:1x720",
+            "testsrc:-1280x720",
+            "testsrc1280x720",
+        ] {
+            let err = s.parse::<VideoSource>().unwrap_err();
+            assert!(err.starts_with("invalid source"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fmt::Display, time::Instant};
+
+use ffmpeg_next as ffmpeg;
+use regex::Regex;
+use serde::Serialize;
+use tracing::{debug, info};
+use xcap::Window;
+
+use super::{
+    AppState,
+    backend::{CaptureBackend, CaptureSource, FramePacer, copy_rgba},
+    capture::{Region, elapsed_pts},
+};
+use crate::error::ServerError;
+
+/// An application window that can be captured on its own.
+#[derive(Clone, Debug, Serialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub pid: u32,
+    pub app_name: String,
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Display for WindowInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} - {} ({}x{})",
+            self.title, self.app_name, self.width, self.height
+        )
+    }
+}
+
+impl WindowInfo {
+    /// The window's current rectangle, in desktop coordinates.
+    pub fn bounds(&self) -> Region {
+        Region {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Enumerate the visible windows that have a title.
+pub fn list_windows() -> Result<Vec<WindowInfo>, ServerError> {
+    let windows = Window::all()
+        .map_err(ServerError::Window)?
+        .into_iter()
+        .filter(|w| !w.is_minimized().unwrap_or(false))
+        .filter_map(|w| {
+            Some(WindowInfo {
+                id: w.id().ok()?,
+                pid: w.pid().unwrap_or_default(),
+                app_name: w.app_name().unwrap_or("Unknown".to_string()),
+                title: w.title().ok().filter(|title| !title.is_empty())?,
+                width: w.width().unwrap_or_default(),
+                height: w.height().unwrap_or_default(),
+                x: w.x().unwrap_or_default(),
+                y: w.y().unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<WindowInfo>>();
+
+    if windows.is_empty() {
+        return Err(ServerError::NoWindows);
+    }
+
+    Ok(windows)
+}
+
+/// Pick a window by id, exact title or regular expression on the title or process name.
+pub fn select_window(windows: &[WindowInfo], selector: &str) -> Result<WindowInfo, ServerError> {
+    let describe = |windows: &[&WindowInfo]| {
+        windows
+            .iter()
+            .map(|w| format!("{}. {}", w.id, w))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let not_found = || ServerError::WindowNotFound {
+        selector: selector.to_string(),
+        available: describe(&windows.iter().collect::<Vec<&WindowInfo>>()),
+    };
+
+    if let Ok(id) = selector.parse::<u32>()
+        && let Some(window) = windows.iter().find(|w| w.id == id)
+    {
+        return Ok(window.clone());
+    }
+
+    if let Some(window) = windows.iter().find(|w| w.title == selector) {
+        return Ok(window.clone());
+    }
+
+    let pattern = Regex::new(selector).map_err(|source| ServerError::InvalidWindowSelector {
+        selector: selector.to_string(),
+        source,
+    })?;
+    let matches = windows
+        .iter()
+        .filter(|w| pattern.is_match(&w.title) || pattern.is_match(&w.app_name))
+        .collect::<Vec<&WindowInfo>>();
+
+    match matches.as_slice() {
+        [window] => Ok((*window).clone()),
+        [] => Err(not_found()),
+        _ => Err(ServerError::AmbiguousWindow {
+            selector: selector.to_string(),
+            matches: describe(&matches),
+        }),
+    }
+}
+
+/// Captures one window with xcap, following it as it moves and resizes.
+///
+/// The capture rectangle in the app state tracks the window so mouse positions stay
+/// relative to it.
+pub(super) struct WindowCapture<'a> {
+    state: &'a AppState,
+    window: Window,
+    title: String,
+    pacer: FramePacer,
+    start: Instant,
+    frame: ffmpeg::frame::Video,
+}
+
+impl<'a> WindowCapture<'a> {
+    pub fn open(state: &'a AppState, target: &WindowInfo) -> Result<Self, ServerError> {
+        let window = Window::all()
+            .map_err(ServerError::Window)?
+            .into_iter()
+            .find(|w| w.id().is_ok_and(|id| id == target.id))
+            .ok_or_else(|| ServerError::Capture(format!("Window '{}' is gone", target.title)))?;
+
+        info!(
+            window = %target,
+            backend = %CaptureBackend::Xcap,
+            "Starting capture"
+        );
+
+        Ok(WindowCapture {
+            state,
+            window,
+            title: target.title.clone(),
+            pacer: FramePacer::new(state.framerate),
+            start: Instant::now(),
+            frame: ffmpeg::frame::Video::empty(),
+        })
+    }
+
+    /// Capture the window into the frame, or return `false` if it cannot be captured now.
+    fn capture(&mut self) -> Result<bool, ServerError> {
+        if self.window.is_minimized().unwrap_or(false) {
+            return Ok(false);
+        }
+
+        // follow the window around the desktop
+        let bounds = Region {
+            x: self.window.x().unwrap_or_default(),
+            y: self.window.y().unwrap_or_default(),
+            width: self.window.width().unwrap_or_default(),
+            height: self.window.height().unwrap_or_default(),
+        };
+        if bounds != self.state.region() {
+            debug!(region = %bounds, "Window moved");
+            self.state.set_region(bounds);
+        }
+
+        let image = self.window.capture_image().map_err(|e| {
+            ServerError::Capture(format!("Failed to capture window '{}': {}", self.title, e))
+        })?;
+        let pts = elapsed_pts(self.start);
+        let (width, height) = (image.width(), image.height());
+        if width < 2 || height < 2 {
+            return Ok(false);
+        }
+
+        if self.frame.width() != width || self.frame.height() != height {
+            self.frame = ffmpeg::frame::Video::new(ffmpeg::format::Pixel::RGBA, width, height);
+        }
+        copy_rgba(image.as_raw(), width, &mut self.frame);
+        self.frame.set_pts(Some(pts));
+        Ok(true)
+    }
+}
+
+impl CaptureSource for WindowCapture<'_> {
+    fn next_frame(&mut self) -> Result<Option<&mut ffmpeg::frame::Video>, ServerError> {
+        loop {
+            self.pacer.wait();
+            if self.capture()? {
+                return Ok(Some(&mut self.frame));
+            }
+
+            // a minimized window, or one too small to encode, keeps showing its last frame
+            if self.frame.width() > 0 {
+                self.frame.set_pts(Some(elapsed_pts(self.start)));
+                return Ok(Some(&mut self.frame));
+            }
+        }
+    }
+
+    fn skip_frame(&mut self) -> bool {
+        self.pacer.wait();
+        true
+    }
+}
@@ -0,0 +1,76 @@
+use tracing::debug;
+use xcb::x;
+
+use crate::error::ServerError;
+
+/// Pick the X display to capture, `display` if given and `$DISPLAY` otherwise, and check
+/// that it can be opened.
+pub fn resolve_x11_display(display: Option<&str>) -> Result<String, ServerError> {
+    let name = match display {
+        Some(display) => display.to_string(),
+        None => std::env::var("DISPLAY")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .ok_or(ServerError::NoX11Display)?,
+    };
+
+    connect(&name)?;
+    debug!(display = name.as_str(), "Opened X display");
+
+    Ok(name)
+}
+
+/// Check that xcap, which lists monitors and windows on `$DISPLAY` only, looks at
+/// `display`.
+///
+/// Programs capturing another display set `$DISPLAY` before starting any thread.
+pub(super) fn check_xcap_display(display: &str) -> Result<(), ServerError> {
+    match std::env::var("DISPLAY") {
+        Ok(current) if current == display => Ok(()),
+        current => Err(ServerError::X11Display {
+            display: display.to_string(),
+            reason: format!(
+                "monitors and windows are listed on $DISPLAY ('{}'), set it to this display",
+                current.unwrap_or_default()
+            ),
+        }),
+    }
+}
+
+/// Reads where the pointer is on an X display.
+pub(super) struct X11Pointer {
+    connection: xcb::Connection,
+    root: x::Window,
+}
+
+impl X11Pointer {
+    pub fn open(display: &str) -> Result<Self, ServerError> {
+        let (connection, screen) = connect(display)?;
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen as usize)
+            .map(|screen| screen.root())
+            .ok_or_else(|| ServerError::X11Display {
+                display: display.to_string(),
+                reason: format!("no screen {}", screen),
+            })?;
+        Ok(X11Pointer { connection, root })
+    }
+
+    /// The pointer position in desktop coordinates, or `None` if the display is gone.
+    pub fn position(&self) -> Option<(i32, i32)> {
+        let cookie = self
+            .connection
+            .send_request(&x::QueryPointer { window: self.root });
+        let reply = self.connection.wait_for_reply(cookie).ok()?;
+        Some((reply.root_x() as i32, reply.root_y() as i32))
+    }
+}
+
+fn connect(display: &str) -> Result<(xcb::Connection, i32), ServerError> {
+    xcb::Connection::connect(Some(display)).map_err(|e| ServerError::X11Display {
+        display: display.to_string(),
+        reason: e.to_string(),
+    })
+}
@@ -0,0 +1,212 @@
+//! Depacketizer for the AV1 RTP payload format, rebuilding OBUs with size fields.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::rtp::{Error, packetizer::Depacketizer};
+
+/// First OBU element continues an OBU from the previous packet.
+const AGGREGATION_Z: u8 = 0x80;
+/// Last OBU element continues in the next packet.
+const AGGREGATION_Y: u8 = 0x40;
+/// Number of OBU elements, 0 when every element carries its size.
+const AGGREGATION_W_MASK: u8 = 0x30;
+
+const OBU_EXTENSION_FLAG: u8 = 0x04;
+const OBU_HAS_SIZE_FIELD: u8 = 0x02;
+
+/// Turns AV1 RTP payloads into low overhead bitstream OBUs, as decoders expect them.
+#[derive(Debug, Default)]
+pub(crate) struct Av1Depacketizer {
+    /// OBU split across packets, collected until its last part arrives.
+    fragment: Option<BytesMut>,
+}
+
+impl Depacketizer for Av1Depacketizer {
+    fn depacketize(&mut self, packet: &Bytes) -> Result<Bytes, Error> {
+        if packet.len() < 2 {
+            return Err(Error::ErrShortPacket);
+        }
+
+        let header = packet[0];
+        let element_count = (header & AGGREGATION_W_MASK) >> 4;
+        let mut rest = &packet[1..];
+        let mut out = BytesMut::new();
+        let mut index = 0;
+
+        while !rest.is_empty() {
+            index += 1;
+
+            // all elements carry their size, except the last when the count is given
+            let element = if element_count == 0 || index < element_count {
+                let (size, size_len) = read_leb128(rest).ok_or(Error::ErrShortPacket)?;
+                rest = &rest[size_len..];
+                if size > rest.len() {
+                    return Err(Error::ErrShortPacket);
+                }
+                let (element, tail) = rest.split_at(size);
+                rest = tail;
+                element
+            } else {
+                std::mem::take(&mut rest)
+            };
+
+            if index == 1 && header & AGGREGATION_Z != 0 {
+                // without its first part the OBU is broken, skip the rest of it
+                let Some(fragment) = self.fragment.as_mut() else {
+                    continue;
+                };
+                fragment.put_slice(element);
+            } else {
+                self.fragment = Some(BytesMut::from(element));
+            }
+
+            if rest.is_empty() && header & AGGREGATION_Y != 0 {
+                break;
+            }
+            if let Some(obu) = self.fragment.take() {
+                write_obu(&mut out, &obu);
+            }
+        }
+
+        Ok(out.freeze())
+    }
+
+    fn is_partition_head(&self, payload: &Bytes) -> bool {
+        payload
+            .first()
+            .is_some_and(|header| header & AGGREGATION_Z == 0)
+    }
+
+    fn is_partition_tail(&self, marker: bool, _payload: &Bytes) -> bool {
+        marker
+    }
+}
+
+/// Append an OBU, adding the size field RTP leaves out.
+fn write_obu(out: &mut BytesMut, obu: &[u8]) {
+    let Some(&header) = obu.first() else {
+        return;
+    };
+    if header & OBU_HAS_SIZE_FIELD != 0 {
+        out.put_slice(obu);
+        return;
+    }
+
+    let header_len = if header & OBU_EXTENSION_FLAG != 0 {
+        2
+    } else {
+        1
+    };
+    if obu.len() < header_len {
+        return;
+    }
+
+    out.put_u8(header | OBU_HAS_SIZE_FIELD);
+    out.put_slice(&obu[1..header_len]);
+    write_leb128(out, obu.len() - header_len);
+    out.put_slice(&obu[header_len..]);
+}
+
+/// Read a LEB128 value, returning it with the number of bytes it took.
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, &byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn write_leb128(out: &mut BytesMut, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.put_u8(byte);
+            return;
+        }
+        out.put_u8(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::{codecs::av1::Av1Payloader, packetizer::Payloader};
+
+    use super::*;
+
+    const OBU_SEQUENCE_HEADER: u8 = 1 << 3;
+    const OBU_FRAME: u8 = 6 << 3;
+
+    /// An OBU of `obu_type` with its size field and `size` bytes of payload.
+    fn obu(obu_type: u8, size: usize) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        out.put_u8(obu_type | OBU_HAS_SIZE_FIELD);
+        write_leb128(&mut out, size);
+        out.extend((0..size).map(|i| (i % 251) as u8));
+        out.to_vec()
+    }
+
+    fn depacketize_all(payloads: &[Bytes]) -> Vec<u8> {
+        let mut depacketizer = Av1Depacketizer::default();
+        let mut out = vec![];
+        for payload in payloads {
+            out.extend_from_slice(&depacketizer.depacketize(payload).unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn leb128_round_trips() {
+        for value in [0, 1, 127, 128, 3000, 16383, 16384, 1 << 28] {
+            let mut out = BytesMut::new();
+            write_leb128(&mut out, value);
+            assert_eq!(read_leb128(&out), Some((value, out.len())));
+        }
+        assert_eq!(read_leb128(&[0x80, 0x80]), None);
+    }
+
+    #[test]
+    fn aggregated_obus_round_trip() {
+        let temporal_unit = [obu(OBU_SEQUENCE_HEADER, 12), obu(OBU_FRAME, 300)].concat();
+
+        let payloads = Av1Payloader::default()
+            .payload(1200, &Bytes::from(temporal_unit.clone()))
+            .unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(depacketize_all(&payloads), temporal_unit);
+    }
+
+    #[test]
+    fn fragmented_obu_round_trips() {
+        let temporal_unit = [obu(OBU_SEQUENCE_HEADER, 12), obu(OBU_FRAME, 3000)].concat();
+
+        let payloads = Av1Payloader::default()
+            .payload(1200, &Bytes::from(temporal_unit.clone()))
+            .unwrap();
+        assert!(payloads.len() >= 3);
+
+        let depacketizer = Av1Depacketizer::default();
+        assert!(depacketizer.is_partition_head(&payloads[0]));
+        assert!(!depacketizer.is_partition_head(&payloads[1]));
+        assert_eq!(depacketize_all(&payloads), temporal_unit);
+    }
+
+    #[test]
+    fn obu_without_its_first_part_is_dropped() {
+        let temporal_unit = obu(OBU_FRAME, 3000);
+
+        let payloads = Av1Payloader::default()
+            .payload(1200, &Bytes::from(temporal_unit))
+            .unwrap();
+        assert!(depacketize_all(&payloads[1..]).is_empty());
+    }
+
+    #[test]
+    fn element_larger_than_the_packet_is_an_error() {
+        let packet = Bytes::from_static(&[0x00, 0x09, OBU_FRAME, 0x00]);
+
+        assert!(Av1Depacketizer::default().depacketize(&packet).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{fmt::Display, str::FromStr};
+
+use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9},
+    rtp::{
+        codecs::{
+            av1::Av1Payloader,
+            h264::{H264Packet, H264Payloader},
+            vp8::{Vp8Packet, Vp8Payloader},
+            vp9::{Vp9Packet, Vp9Payloader},
+        },
+        packetizer::{Depacketizer, Payloader},
+    },
+};
+
+use super::{
+    av1::Av1Depacketizer,
+    hevc::{HevcDepacketizer, HevcPayloader},
+};
+
+/// MIME type of H.265 in SDP.
+pub const MIME_TYPE_H265: &str = "video/H265";
+
+/// Video codec used for the stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    #[serde(alias = "hevc")]
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl VideoCodec {
+    /// Every codec, in the order they are offered.
+    pub const ALL: &[VideoCodec] = &[
+        VideoCodec::H264,
+        VideoCodec::H265,
+        VideoCodec::Vp8,
+        VideoCodec::Vp9,
+        VideoCodec::Av1,
+    ];
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => MIME_TYPE_H264,
+            VideoCodec::H265 => MIME_TYPE_H265,
+            VideoCodec::Vp8 => MIME_TYPE_VP8,
+            VideoCodec::Vp9 => MIME_TYPE_VP9,
+            VideoCodec::Av1 => MIME_TYPE_AV1,
+        }
+    }
+
+    /// Codecs offered in an SDP, in the order they are listed.
+    pub fn from_sdp(sdp: &str) -> Vec<VideoCodec> {
+        let mut codecs = vec![];
+        for line in sdp.lines() {
+            // a=rtpmap:<payload type> <encoding name>/<clock rate>
+            let Some(rtpmap) = line.strip_prefix("a=rtpmap:") else {
+                continue;
+            };
+            let name = rtpmap
+                .split_whitespace()
+                .nth(1)
+                .and_then(|encoding| encoding.split('/').next());
+            if let Some(codec) =
+                name.and_then(|name| Self::from_mime_type(&format!("video/{}", name)))
+                && !codecs.contains(&codec)
+            {
+                codecs.push(codec);
+            }
+        }
+        codecs
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<VideoCodec> {
+        VideoCodec::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.mime_type().eq_ignore_ascii_case(mime_type))
+    }
+
+    pub(crate) fn payload_type(self) -> u8 {
+        match self {
+            VideoCodec::H264 => 102,
+            VideoCodec::H265 => 104,
+            VideoCodec::Vp8 => 96,
+            VideoCodec::Vp9 => 98,
+            VideoCodec::Av1 => 45,
+        }
+    }
+
+    /// Format parameters a receiver announces, FFmpeg's decoders take every profile and
+    /// level these allow. The server answers H.264 with the format it encodes instead.
+    pub(crate) fn sdp_fmtp_line(self) -> String {
+        match self {
+            VideoCodec::H264 => {
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640034".to_string()
+            }
+            VideoCodec::H265 => "level-id=93;profile-id=1;tier-flag=0;tx-mode=SRST".to_string(),
+            VideoCodec::Vp8 => String::new(),
+            VideoCodec::Vp9 => "profile-id=0".to_string(),
+            VideoCodec::Av1 => "level-idx=5;profile=0;tier=0".to_string(),
+        }
+    }
+
+    pub(crate) fn ffmpeg_id(self) -> ffmpeg::codec::Id {
+        match self {
+            VideoCodec::H264 => ffmpeg::codec::Id::H264,
+            VideoCodec::H265 => ffmpeg::codec::Id::HEVC,
+            VideoCodec::Vp8 => ffmpeg::codec::Id::VP8,
+            VideoCodec::Vp9 => ffmpeg::codec::Id::VP9,
+            VideoCodec::Av1 => ffmpeg::codec::Id::AV1,
+        }
+    }
+
+    /// Splits encoded frames into RTP payloads.
+    pub(crate) fn payloader(self) -> Box<dyn Payloader + Send + Sync> {
+        match self {
+            VideoCodec::H264 => Box::new(H264Payloader::default()),
+            VideoCodec::H265 => Box::new(HevcPayloader),
+            VideoCodec::Vp8 => Box::new(Vp8Payloader::default()),
+            VideoCodec::Vp9 => Box::new(Vp9Payloader::default()),
+            VideoCodec::Av1 => Box::new(Av1Payloader::default()),
+        }
+    }
+
+    /// Turns RTP payloads back into frames, as an Annex B stream for H.264 and H.265.
+    pub(crate) fn depacketizer(self) -> Box<dyn Depacketizer + Send> {
+        match self {
+            VideoCodec::H264 => Box::new(H264Packet::default()),
+            VideoCodec::H265 => Box::new(HevcDepacketizer::default()),
+            VideoCodec::Vp8 => Box::new(Vp8Packet::default()),
+            VideoCodec::Vp9 => Box::new(Vp9Packet::default()),
+            VideoCodec::Av1 => Box::new(Av1Depacketizer::default()),
+        }
+    }
+}
+
+impl Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoCodec::H264 => write!(f, "h264"),
+            VideoCodec::H265 => write!(f, "h265"),
+            VideoCodec::Vp8 => write!(f, "vp8"),
+            VideoCodec::Vp9 => write!(f, "vp9"),
+            VideoCodec::Av1 => write!(f, "av1"),
+        }
+    }
+}
+
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "h264" => Ok(VideoCodec::H264),
+            "h265" | "hevc" => Ok(VideoCodec::H265),
+            "vp8" => Ok(VideoCodec::Vp8),
+            "vp9" => Ok(VideoCodec::Vp9),
+            "av1" => Ok(VideoCodec::Av1),
+            _ => Err(format!(
+                "invalid codec '{}', expected one of: h264, h265, vp8, vp9, av1",
+                s
+            )),
+        }
+    }
+}
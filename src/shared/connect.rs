@@ -1,35 +1,62 @@
 use std::sync::Arc;
 
-use anyhow::Result;
 use webrtc::{
     api::{
         APIBuilder,
-        media_engine::{MIME_TYPE_H264, MediaEngine},
+        interceptor_registry::{configure_rtcp_reports, configure_twcc},
+        media_engine::MediaEngine,
+    },
+    error::Result,
+    interceptor::{
+        nack::{generator::Generator, responder::Responder},
+        registry::Registry,
     },
     peer_connection::{RTCPeerConnection, configuration::RTCConfiguration},
-    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    rtp_transceiver::{
+        RTCPFeedback,
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+    },
 };
 
-pub async fn create_peer_connection() -> Result<Arc<RTCPeerConnection>> {
+use super::VideoCodec;
+
+/// Packets kept for retransmission when no other size is configured.
+pub const DEFAULT_NACK_BUFFER: u16 = 1024;
+
+/// Create a peer connection for `codecs`, in order of preference and each with the fmtp
+/// line to announce, with NACK, RTCP report and TWCC interceptors.
+///
+/// `nack_buffer` is how many sent packets are kept to answer NACKs, and how far back
+/// missing packets are tracked on the receiving side. It is rounded up to a power of two
+/// between 64 and 32768.
+pub async fn create_peer_connection(
+    codecs: &[(VideoCodec, String)],
+    nack_buffer: u16,
+) -> Result<Arc<RTCPeerConnection>> {
     let mut m = MediaEngine::default();
-    m.register_codec(
-        RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_H264.to_owned(),
-                clock_rate: 90000,
-                channels: 0,
-                sdp_fmtp_line:
-                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f"
-                        .to_string(),
+    for (codec, sdp_fmtp_line) in codecs {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: codec.mime_type().to_owned(),
+                    clock_rate: 90000,
+                    channels: 0,
+                    sdp_fmtp_line: sdp_fmtp_line.clone(),
+                    ..Default::default()
+                },
+                payload_type: codec.payload_type(),
                 ..Default::default()
             },
-            payload_type: 102,
-            ..Default::default()
-        },
-        RTPCodecType::Video,
-    )?;
+            RTPCodecType::Video,
+        )?;
+    }
+
+    let registry = configure_interceptors(Registry::new(), &mut m, nack_buffer)?;
 
-    let api = APIBuilder::new().with_media_engine(m).build();
+    let api = APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .build();
     let config = RTCConfiguration {
         ice_servers: vec![],
         ..Default::default()
@@ -38,3 +65,33 @@ pub async fn create_peer_connection() -> Result<Arc<RTCPeerConnection>> {
 
     Ok(Arc::new(pc))
 }
+
+/// The default interceptors, with the NACK buffers sized to `nack_buffer` packets.
+fn configure_interceptors(
+    mut registry: Registry,
+    media_engine: &mut MediaEngine,
+    nack_buffer: u16,
+) -> Result<Registry> {
+    for parameter in ["", "pli"] {
+        media_engine.register_feedback(
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: parameter.to_owned(),
+            },
+            RTPCodecType::Video,
+        );
+    }
+
+    let log2_size = nack_buffer
+        .clamp(64, 32768)
+        .next_power_of_two()
+        .trailing_zeros() as u8;
+    // lost packets are resent as they were, on the media stream, as webrtc-rs has no RTX
+    registry.add(Box::new(Responder::builder().with_log2_size(log2_size)));
+    registry.add(Box::new(
+        Generator::builder().with_log2_size_minus_6(log2_size - 6),
+    ));
+
+    let registry = configure_rtcp_reports(registry);
+    configure_twcc(registry, media_engine)
+}
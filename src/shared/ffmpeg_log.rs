@@ -0,0 +1,129 @@
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    str::FromStr,
+    sync::{
+        Once,
+        atomic::{AtomicI32, Ordering},
+    },
+};
+
+use ffmpeg_next as ffmpeg;
+use serde::Deserialize;
+use tracing::{debug, error, info, trace, warn};
+
+/// Verbosity of FFmpeg's own log output, which is forwarded to `tracing` under the `ffmpeg` target.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FfmpegLogLevel {
+    #[default]
+    Quiet,
+    Fatal,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl FfmpegLogLevel {
+    fn as_av_level(self) -> c_int {
+        match self {
+            FfmpegLogLevel::Quiet => ffmpeg::ffi::AV_LOG_QUIET,
+            FfmpegLogLevel::Fatal => ffmpeg::ffi::AV_LOG_FATAL,
+            FfmpegLogLevel::Error => ffmpeg::ffi::AV_LOG_ERROR,
+            FfmpegLogLevel::Warning => ffmpeg::ffi::AV_LOG_WARNING,
+            FfmpegLogLevel::Info => ffmpeg::ffi::AV_LOG_INFO,
+            FfmpegLogLevel::Verbose => ffmpeg::ffi::AV_LOG_VERBOSE,
+            FfmpegLogLevel::Debug => ffmpeg::ffi::AV_LOG_DEBUG,
+            FfmpegLogLevel::Trace => ffmpeg::ffi::AV_LOG_TRACE,
+        }
+    }
+}
+
+impl FromStr for FfmpegLogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "quiet" => Ok(FfmpegLogLevel::Quiet),
+            "fatal" => Ok(FfmpegLogLevel::Fatal),
+            "error" => Ok(FfmpegLogLevel::Error),
+            "warning" => Ok(FfmpegLogLevel::Warning),
+            "info" => Ok(FfmpegLogLevel::Info),
+            "verbose" => Ok(FfmpegLogLevel::Verbose),
+            "debug" => Ok(FfmpegLogLevel::Debug),
+            "trace" => Ok(FfmpegLogLevel::Trace),
+            _ => Err(format!(
+                "invalid FFmpeg log level '{}', expected one of: quiet, fatal, error, warning, info, verbose, debug, trace",
+                s
+            )),
+        }
+    }
+}
+
+static FFMPEG_LOG_LEVEL: AtomicI32 = AtomicI32::new(ffmpeg::ffi::AV_LOG_QUIET);
+static FFMPEG_INIT: Once = Once::new();
+
+/// Set how much of FFmpeg's log output is forwarded. FFmpeg is quiet by default.
+pub fn set_ffmpeg_log_level(level: FfmpegLogLevel) {
+    FFMPEG_LOG_LEVEL.store(level.as_av_level(), Ordering::Relaxed);
+    unsafe {
+        ffmpeg::ffi::av_log_set_level(level.as_av_level());
+    }
+}
+
+/// Initialize FFmpeg and route its log callback into `tracing`.
+pub(crate) fn init_ffmpeg() -> Result<(), ffmpeg::Error> {
+    FFMPEG_INIT.call_once(|| unsafe {
+        ffmpeg::ffi::av_log_set_level(FFMPEG_LOG_LEVEL.load(Ordering::Relaxed));
+        ffmpeg::ffi::av_log_set_callback(Some(ffmpeg_log_callback));
+    });
+    ffmpeg::init()
+}
+
+unsafe extern "C" fn ffmpeg_log_callback(
+    avcl: *mut c_void,
+    level: c_int,
+    fmt: *const c_char,
+    vl: ffmpeg::ffi::va_list,
+) {
+    if level > FFMPEG_LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut line = [0 as c_char; 1024];
+    let mut print_prefix: c_int = 1;
+    let message = unsafe {
+        let len = ffmpeg::ffi::av_log_format_line2(
+            avcl,
+            level,
+            fmt,
+            vl,
+            line.as_mut_ptr(),
+            line.len() as c_int,
+            &mut print_prefix,
+        );
+        if len < 0 {
+            return;
+        }
+        CStr::from_ptr(line.as_ptr()).to_string_lossy()
+    };
+
+    let message = message.trim_end();
+    if message.is_empty() {
+        return;
+    }
+
+    if level <= ffmpeg::ffi::AV_LOG_ERROR {
+        error!(target: "ffmpeg", "{}", message);
+    } else if level <= ffmpeg::ffi::AV_LOG_WARNING {
+        warn!(target: "ffmpeg", "{}", message);
+    } else if level <= ffmpeg::ffi::AV_LOG_INFO {
+        info!(target: "ffmpeg", "{}", message);
+    } else if level <= ffmpeg::ffi::AV_LOG_DEBUG {
+        debug!(target: "ffmpeg", "{}", message);
+    } else {
+        trace!(target: "ffmpeg", "{}", message);
+    }
+}
@@ -0,0 +1,303 @@
+//! RTP payload format for H.265 (RFC 7798), without DONL or PACI support.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::rtp::{
+    Error,
+    packetizer::{Depacketizer, Payloader},
+};
+
+const ANNEXB_START_CODE: &[u8] = &[0, 0, 0, 1];
+
+const NAL_HEADER_SIZE: usize = 2;
+const FU_HEADER_SIZE: usize = 1;
+
+const AGGREGATION_PACKET: u8 = 48;
+const FRAGMENTATION_UNIT: u8 = 49;
+const PACI_PACKET: u8 = 50;
+
+const FU_START: u8 = 0x80;
+const FU_END: u8 = 0x40;
+
+fn nal_type(header: u8) -> u8 {
+    (header >> 1) & 0x3f
+}
+
+/// Splits an Annex B access unit into single NAL unit packets and fragmentation units.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HevcPayloader;
+
+impl Payloader for HevcPayloader {
+    fn payload(&mut self, mtu: usize, payload: &Bytes) -> Result<Vec<Bytes>, Error> {
+        if mtu <= NAL_HEADER_SIZE + FU_HEADER_SIZE {
+            return Ok(vec![]);
+        }
+
+        let mut payloads = vec![];
+        for nal in split_annexb(payload) {
+            if nal.len() <= NAL_HEADER_SIZE {
+                continue;
+            }
+            if nal.len() <= mtu {
+                payloads.push(nal);
+                continue;
+            }
+
+            // the payload header takes the NAL header with the type replaced,
+            // the FU header carries the original type
+            let payload_header = [(nal[0] & 0x81) | (FRAGMENTATION_UNIT << 1), nal[1]];
+            let fragment_size = mtu - NAL_HEADER_SIZE - FU_HEADER_SIZE;
+            let fragments = nal[NAL_HEADER_SIZE..].chunks(fragment_size);
+            let last = fragments.len() - 1;
+
+            for (i, fragment) in fragments.enumerate() {
+                let mut fu_header = nal_type(nal[0]);
+                if i == 0 {
+                    fu_header |= FU_START;
+                }
+                if i == last {
+                    fu_header |= FU_END;
+                }
+
+                let mut packet =
+                    BytesMut::with_capacity(NAL_HEADER_SIZE + FU_HEADER_SIZE + fragment.len());
+                packet.put_slice(&payload_header);
+                packet.put_u8(fu_header);
+                packet.put_slice(fragment);
+                payloads.push(packet.freeze());
+            }
+        }
+
+        Ok(payloads)
+    }
+
+    fn clone_to(&self) -> Box<dyn Payloader + Send + Sync> {
+        Box::new(*self)
+    }
+}
+
+/// NAL units of an Annex B stream, without their start codes.
+fn split_annexb(data: &Bytes) -> Vec<Bytes> {
+    let mut nals = vec![];
+    let mut start = None;
+    let mut i = 0;
+
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                nals.push(trim_trailing_zeros(data.slice(start..i)));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    match start {
+        Some(start) => nals.push(data.slice(start..)),
+        None if !data.is_empty() => nals.push(data.clone()),
+        None => {}
+    }
+
+    nals
+}
+
+/// Drop the leading zero of a four byte start code that follows a NAL unit.
+fn trim_trailing_zeros(nal: Bytes) -> Bytes {
+    let end = nal.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+    nal.slice(..end)
+}
+
+/// Rebuilds an Annex B stream from H.265 RTP payloads, joining fragmentation units.
+#[derive(Debug, Default)]
+pub(crate) struct HevcDepacketizer {
+    fragments: Option<BytesMut>,
+}
+
+impl Depacketizer for HevcDepacketizer {
+    fn depacketize(&mut self, packet: &Bytes) -> Result<Bytes, Error> {
+        if packet.len() <= NAL_HEADER_SIZE {
+            return Err(Error::ErrShortPacket);
+        }
+
+        let mut out = BytesMut::new();
+        match nal_type(packet[0]) {
+            AGGREGATION_PACKET => {
+                self.fragments = None;
+
+                let mut rest = &packet[NAL_HEADER_SIZE..];
+                while rest.len() >= 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    rest = &rest[2..];
+                    if size > rest.len() {
+                        return Err(Error::ErrShortPacket);
+                    }
+                    out.put_slice(ANNEXB_START_CODE);
+                    out.put_slice(&rest[..size]);
+                    rest = &rest[size..];
+                }
+            }
+            FRAGMENTATION_UNIT => {
+                if packet.len() <= NAL_HEADER_SIZE + FU_HEADER_SIZE {
+                    return Err(Error::ErrShortPacket);
+                }
+
+                let fu_header = packet[NAL_HEADER_SIZE];
+                if fu_header & FU_START != 0 {
+                    let mut nal = BytesMut::new();
+                    nal.put_u8((packet[0] & 0x81) | ((fu_header & 0x3f) << 1));
+                    nal.put_u8(packet[1]);
+                    self.fragments = Some(nal);
+                }
+
+                // without its first fragment the unit is broken, skip the rest of it
+                let Some(nal) = self.fragments.as_mut() else {
+                    return Ok(Bytes::new());
+                };
+                nal.put_slice(&packet[NAL_HEADER_SIZE + FU_HEADER_SIZE..]);
+
+                if fu_header & FU_END != 0
+                    && let Some(nal) = self.fragments.take()
+                {
+                    out.put_slice(ANNEXB_START_CODE);
+                    out.put_slice(&nal);
+                }
+            }
+            PACI_PACKET => {
+                self.fragments = None;
+            }
+            _ => {
+                self.fragments = None;
+                out.put_slice(ANNEXB_START_CODE);
+                out.put_slice(packet);
+            }
+        }
+
+        Ok(out.freeze())
+    }
+
+    fn is_partition_head(&self, payload: &Bytes) -> bool {
+        payload.len() > NAL_HEADER_SIZE
+            && (nal_type(payload[0]) != FRAGMENTATION_UNIT
+                || payload[NAL_HEADER_SIZE] & FU_START != 0)
+    }
+
+    fn is_partition_tail(&self, marker: bool, _payload: &Bytes) -> bool {
+        marker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Annex B access unit made of `nals`, each behind a four byte start code.
+    fn annexb(nals: &[&[u8]]) -> Bytes {
+        let mut out = BytesMut::new();
+        for nal in nals {
+            out.put_slice(ANNEXB_START_CODE);
+            out.put_slice(nal);
+        }
+        out.freeze()
+    }
+
+    /// An IDR slice (type 19) of `size` bytes.
+    fn idr_slice(size: usize) -> Vec<u8> {
+        let mut nal = vec![19 << 1, 0x01];
+        nal.extend((0..size - NAL_HEADER_SIZE).map(|i| (i % 251) as u8 + 1));
+        nal
+    }
+
+    fn depacketize_all(payloads: &[Bytes]) -> Vec<u8> {
+        let mut depacketizer = HevcDepacketizer::default();
+        let mut out = vec![];
+        for payload in payloads {
+            out.extend_from_slice(&depacketizer.depacketize(payload).unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn small_nal_units_round_trip_as_single_packets() {
+        let vps = [32 << 1, 0x01, 0x0c, 0x01, 0xff];
+        let sps = [33 << 1, 0x01, 0x01, 0x60, 0x90];
+        let access_unit = annexb(&[&vps, &sps]);
+
+        let payloads = HevcPayloader.payload(1200, &access_unit).unwrap();
+        assert_eq!(
+            payloads,
+            vec![Bytes::copy_from_slice(&vps), Bytes::copy_from_slice(&sps)]
+        );
+        assert_eq!(depacketize_all(&payloads), access_unit);
+    }
+
+    #[test]
+    fn three_byte_start_codes_are_split() {
+        let access_unit =
+            Bytes::from_static(&[0, 0, 1, 0x40, 0x01, 0xaa, 0, 0, 0, 1, 0x42, 0x01, 0xbb]);
+
+        let payloads = HevcPayloader.payload(1200, &access_unit).unwrap();
+        assert_eq!(
+            payloads,
+            vec![
+                Bytes::from_static(&[0x40, 0x01, 0xaa]),
+                Bytes::from_static(&[0x42, 0x01, 0xbb]),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_nal_unit_round_trips_through_fragmentation_units() {
+        let nal = idr_slice(3000);
+        let access_unit = annexb(&[&nal]);
+
+        let payloads = HevcPayloader.payload(1200, &access_unit).unwrap();
+        assert_eq!(payloads.len(), 3);
+        for (i, payload) in payloads.iter().enumerate() {
+            assert!(payload.len() <= 1200);
+            assert_eq!(nal_type(payload[0]), FRAGMENTATION_UNIT);
+            assert_eq!(payload[1], nal[1]);
+
+            let fu_header = payload[NAL_HEADER_SIZE];
+            assert_eq!(fu_header & 0x3f, 19);
+            assert_eq!(fu_header & FU_START != 0, i == 0);
+            assert_eq!(fu_header & FU_END != 0, i == payloads.len() - 1);
+        }
+
+        let mut depacketizer = HevcDepacketizer::default();
+        assert!(depacketizer.is_partition_head(&payloads[0]));
+        assert!(!depacketizer.is_partition_head(&payloads[1]));
+        assert!(depacketizer.depacketize(&payloads[0]).unwrap().is_empty());
+        assert!(depacketizer.depacketize(&payloads[1]).unwrap().is_empty());
+        assert_eq!(depacketizer.depacketize(&payloads[2]).unwrap(), access_unit);
+    }
+
+    #[test]
+    fn fragmentation_units_without_their_start_are_dropped() {
+        let nal = idr_slice(3000);
+        let payloads = HevcPayloader.payload(1200, &annexb(&[&nal])).unwrap();
+
+        assert!(depacketize_all(&payloads[1..]).is_empty());
+    }
+
+    #[test]
+    fn aggregation_packet_is_split_into_nal_units() {
+        let vps = [32 << 1, 0x01, 0x0c];
+        let pps = [34 << 1, 0x01, 0xc1, 0x72];
+        let mut packet = BytesMut::new();
+        packet.put_slice(&[AGGREGATION_PACKET << 1, 0x01]);
+        for nal in [&vps[..], &pps[..]] {
+            packet.put_u16(nal.len() as u16);
+            packet.put_slice(nal);
+        }
+
+        assert_eq!(depacketize_all(&[packet.freeze()]), annexb(&[&vps, &pps]));
+    }
+
+    #[test]
+    fn truncated_aggregation_packet_is_an_error() {
+        let packet = Bytes::from_static(&[AGGREGATION_PACKET << 1, 0x01, 0x00, 0x09, 0x40, 0x01]);
+
+        assert!(HevcDepacketizer::default().depacketize(&packet).is_err());
+    }
+}
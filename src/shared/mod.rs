@@ -1,11 +1,33 @@
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
+mod av1;
+mod codec;
 mod connect;
+mod ffmpeg_log;
+mod hevc;
+mod probe;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SdpData {
     pub sdp: String,
     pub password: Option<String>,
+    /// Codec the server picked, sent with its answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamInfo>,
+}
+
+/// Codec and encoder the server streams to a viewer with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub codec: VideoCodec,
+    pub encoder: String,
+}
+
+/// Body of an error response from the server's SDP endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub error: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,4 +36,31 @@ pub struct MousePosition {
     pub y: f64,
 }
 
-pub use connect::create_peer_connection;
+/// Handle used to stop a running server or client from another task.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    tx: broadcast::Sender<()>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        let (tx, _) = broadcast::channel::<()>(1);
+        ShutdownHandle { tx }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.tx.subscribe()
+    }
+
+    /// Signal all tasks to stop.
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(());
+    }
+}
+
+pub use codec::{MIME_TYPE_H265, VideoCodec};
+pub use connect::{DEFAULT_NACK_BUFFER, create_peer_connection};
+pub(crate) use ffmpeg_log::init_ffmpeg;
+pub use ffmpeg_log::{FfmpegLogLevel, set_ffmpeg_log_level};
+pub use probe::ProbeResult;
+pub(crate) use probe::{test_decode, test_encode};
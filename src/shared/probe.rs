@@ -0,0 +1,93 @@
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+
+use super::VideoCodec;
+
+/// Size of the frame encoders and decoders are tried with, above the minimum of every
+/// hardware encoder.
+const TEST_WIDTH: u32 = 320;
+const TEST_HEIGHT: u32 = 240;
+
+/// Outcome of trying one encoder or decoder.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProbeResult {
+    pub codec: VideoCodec,
+    /// FFmpeg's name of the encoder or decoder.
+    pub name: String,
+    pub hardware: bool,
+    /// Why it could not be used, or `None` when it works.
+    pub error: Option<String>,
+}
+
+impl ProbeResult {
+    pub(crate) fn new(
+        codec: VideoCodec,
+        name: &str,
+        hardware: bool,
+        result: Result<(), String>,
+    ) -> Self {
+        ProbeResult {
+            codec,
+            name: name.to_string(),
+            hardware,
+            error: result.err(),
+        }
+    }
+}
+
+/// Open `encoder` with `options` and encode a black frame, returning the packets.
+pub(crate) fn test_encode(
+    encoder: ffmpeg::Codec,
+    options: ffmpeg::Dictionary,
+) -> Result<Vec<ffmpeg::Packet>, String> {
+    let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder)
+        .encoder()
+        .video()
+        .map_err(|e| e.to_string())?;
+    encoder_ctx.set_width(TEST_WIDTH);
+    encoder_ctx.set_height(TEST_HEIGHT);
+    encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
+    encoder_ctx.set_frame_rate(Some(ffmpeg::Rational(30, 1)));
+    encoder_ctx.set_time_base(ffmpeg::Rational(1, 30));
+    let mut encoder = encoder_ctx
+        .open_with(options)
+        .map_err(|e| format!("failed to open: {}", e))?;
+
+    let mut frame =
+        ffmpeg::frame::Video::new(ffmpeg::format::Pixel::YUV420P, TEST_WIDTH, TEST_HEIGHT);
+    frame.data_mut(0).fill(16);
+    frame.data_mut(1).fill(128);
+    frame.data_mut(2).fill(128);
+    frame.set_pts(Some(0));
+
+    encoder
+        .send_frame(&frame)
+        .and_then(|_| encoder.send_eof())
+        .map_err(|e| format!("failed to encode: {}", e))?;
+    let mut packets = vec![];
+    let mut packet = ffmpeg::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packets.push(packet.clone());
+    }
+    if packets.is_empty() {
+        return Err("encoded nothing".to_string());
+    }
+    Ok(packets)
+}
+
+/// Decode `packets` with an opened decoder, which has to return at least one frame.
+pub(crate) fn test_decode(
+    decoder: &mut ffmpeg::decoder::Video,
+    packets: &[ffmpeg::Packet],
+) -> Result<(), String> {
+    for packet in packets {
+        decoder
+            .send_packet(packet)
+            .map_err(|e| format!("failed to decode: {}", e))?;
+    }
+    let _ = decoder.send_eof();
+    let mut frame = ffmpeg::frame::Video::empty();
+    decoder
+        .receive_frame(&mut frame)
+        .map_err(|e| format!("decoded nothing: {}", e))
+}